@@ -0,0 +1,348 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use bluer::{
+    Address, Session, Uuid,
+    agent::{Agent, AgentHandle, ReqError, ReqResult},
+    id::ServiceClass,
+};
+use tokio::sync::{mpsc::Sender, oneshot};
+
+use crate::{app::AppEvent, config};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AgentRequest {
+    RequestPinCode,
+    RequestPasskey,
+    DisplayPinCode { pin_code: String },
+    DisplayPasskey { passkey: u32 },
+    RequestConfirmation { passkey: u32 },
+    AuthorizeService { service: Uuid },
+}
+
+#[derive(Debug)]
+pub enum AgentReply {
+    PinCode(String),
+    Accept,
+    Reject,
+}
+
+#[derive(Debug)]
+pub struct AgentPrompt {
+    pub device_name: String,
+    pub request: AgentRequest,
+    pub timeout: Duration,
+    /// `None` for requests that only display something and don't expect an answer.
+    pub reply_tx: Option<oneshot::Sender<AgentReply>>,
+    /// Fires when BlueZ no longer needs the prompt to be shown.
+    pub cancel: Option<oneshot::Receiver<()>>,
+}
+
+impl AgentRequest {
+    pub fn message(&self, device_name: &str) -> String {
+        match self {
+            AgentRequest::RequestPinCode => {
+                format!("Enter the PIN code for \"{}\"", device_name)
+            }
+            AgentRequest::RequestPasskey => {
+                format!("Enter the 6-digit passkey for \"{}\"", device_name)
+            }
+            AgentRequest::DisplayPinCode { pin_code } => {
+                format!(
+                    "Type {} on \"{}\" and press Enter to finish pairing",
+                    pin_code, device_name
+                )
+            }
+            AgentRequest::DisplayPasskey { passkey } => {
+                format!(
+                    "Type {:06} on \"{}\" and press Enter to finish pairing",
+                    passkey, device_name
+                )
+            }
+            AgentRequest::RequestConfirmation { passkey } => {
+                format!(
+                    "Confirm that \"{}\" is showing the passkey {:06}",
+                    device_name, passkey
+                )
+            }
+            AgentRequest::AuthorizeService { service } => {
+                let service_name = ServiceClass::try_from(*service)
+                    .map(|service| service.to_string())
+                    .unwrap_or_else(|_| service.to_string());
+
+                format!(
+                    "Allow \"{}\" to use the {} service?",
+                    device_name, service_name
+                )
+            }
+        }
+    }
+}
+
+/// Only reads the name, since BlueZ's own timeout for the agent is already running.
+async fn device_name(session: &Session, adapter: &str, address: Address) -> String {
+    let device = match session
+        .adapter(adapter)
+        .and_then(|adapter| adapter.device(address))
+    {
+        Ok(device) => device,
+        Err(_) => return address.to_string(),
+    };
+
+    if let Ok(alias) = device.alias().await
+        && !alias.is_empty()
+    {
+        return alias;
+    }
+
+    match device.name().await {
+        Ok(Some(name)) => name,
+        _ => address.to_string(),
+    }
+}
+
+/// Shows a prompt through the UI and waits for the user to answer it.
+async fn ask(
+    session: &Session,
+    app_tx: &Sender<AppEvent>,
+    adapter: &str,
+    address: Address,
+    request: AgentRequest,
+) -> ReqResult<AgentReply> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    // How long BlueZ is kept waiting on the user before the request is cancelled.
+    let timeout = Duration::from_millis(config::current().bluetooth.agent_prompt_timeout_ms);
+
+    let prompt = AgentPrompt {
+        device_name: device_name(session, adapter, address).await,
+        request,
+        timeout,
+        reply_tx: Some(reply_tx),
+        cancel: None,
+    };
+
+    if app_tx.send(AppEvent::Prompt(prompt)).await.is_err() {
+        return Err(ReqError::Canceled);
+    }
+
+    match tokio::time::timeout(timeout, reply_rx).await {
+        Ok(Ok(reply)) => Ok(reply),
+        _ => Err(ReqError::Canceled),
+    }
+}
+
+/// Shows a prompt through the UI without waiting for it to be dismissed.
+async fn notify(
+    session: &Session,
+    app_tx: &Sender<AppEvent>,
+    adapter: &str,
+    address: Address,
+    request: AgentRequest,
+    cancel: oneshot::Receiver<()>,
+) -> ReqResult<()> {
+    let prompt = AgentPrompt {
+        device_name: device_name(session, adapter, address).await,
+        request,
+        timeout: Duration::from_millis(config::current().bluetooth.agent_prompt_timeout_ms),
+        reply_tx: None,
+        cancel: Some(cancel),
+    };
+
+    app_tx
+        .send(AppEvent::Prompt(prompt))
+        .await
+        .map_err(|_| ReqError::Canceled)
+}
+
+/// The code currently on screen. BlueZ sends `DisplayPasskey` again for every digit typed on
+/// the device, so the dialog is only replaced when the device or the code changes.
+struct Displayed {
+    device: Address,
+    request: AgentRequest,
+    /// Dropping this closes the dialog.
+    _close: oneshot::Sender<()>,
+}
+
+async fn display(
+    session: &Session,
+    app_tx: &Sender<AppEvent>,
+    displayed: &Arc<Mutex<Option<Displayed>>>,
+    adapter: &str,
+    address: Address,
+    request: AgentRequest,
+    cancel: oneshot::Receiver<()>,
+) -> ReqResult<()> {
+    let close_rx = {
+        let mut displayed = displayed.lock().unwrap();
+
+        if displayed
+            .as_ref()
+            .is_some_and(|shown| shown.device == address && shown.request == request)
+        {
+            None
+        } else {
+            let (close_tx, close_rx) = oneshot::channel();
+            *displayed = Some(Displayed {
+                device: address,
+                request: request.clone(),
+                _close: close_tx,
+            });
+            Some(close_rx)
+        }
+    };
+
+    // `cancel` also fires, with an error, when the next request supersedes this one. Only an
+    // actual Cancel from BlueZ closes the dialog.
+    tokio::spawn({
+        let displayed = displayed.clone();
+        async move {
+            if cancel.await.is_ok() {
+                let mut displayed = displayed.lock().unwrap();
+
+                if displayed
+                    .as_ref()
+                    .is_some_and(|shown| shown.device == address)
+                {
+                    *displayed = None;
+                }
+            }
+        }
+    });
+
+    match close_rx {
+        Some(close_rx) => notify(session, app_tx, adapter, address, request, close_rx).await,
+        None => Ok(()),
+    }
+}
+
+pub async fn register_agent(session: &Session, app_tx: Sender<AppEvent>) -> Result<AgentHandle> {
+    let displayed = Arc::new(Mutex::new(None));
+
+    let agent = Agent {
+        request_default: true,
+        request_pin_code: Some(Box::new({
+            let session = session.clone();
+            let app_tx = app_tx.clone();
+            move |req| {
+                let session = session.clone();
+                let app_tx = app_tx.clone();
+                Box::pin(async move {
+                    let request = AgentRequest::RequestPinCode;
+                    match ask(&session, &app_tx, &req.adapter, req.device, request).await? {
+                        AgentReply::PinCode(pin_code) if !pin_code.is_empty() => Ok(pin_code),
+                        _ => Err(ReqError::Rejected),
+                    }
+                })
+            }
+        })),
+        request_passkey: Some(Box::new({
+            let session = session.clone();
+            let app_tx = app_tx.clone();
+            move |req| {
+                let session = session.clone();
+                let app_tx = app_tx.clone();
+                Box::pin(async move {
+                    let request = AgentRequest::RequestPasskey;
+                    match ask(&session, &app_tx, &req.adapter, req.device, request).await? {
+                        AgentReply::PinCode(passkey) => passkey
+                            .parse::<u32>()
+                            .ok()
+                            .filter(|passkey| *passkey <= 999_999)
+                            .ok_or(ReqError::Rejected),
+                        _ => Err(ReqError::Rejected),
+                    }
+                })
+            }
+        })),
+        display_pin_code: Some(Box::new({
+            let session = session.clone();
+            let app_tx = app_tx.clone();
+            let displayed = displayed.clone();
+            move |req| {
+                let session = session.clone();
+                let app_tx = app_tx.clone();
+                let displayed = displayed.clone();
+                Box::pin(async move {
+                    let request = AgentRequest::DisplayPinCode {
+                        pin_code: req.pincode,
+                    };
+                    display(
+                        &session,
+                        &app_tx,
+                        &displayed,
+                        &req.adapter,
+                        req.device,
+                        request,
+                        req.cancel,
+                    )
+                    .await
+                })
+            }
+        })),
+        display_passkey: Some(Box::new({
+            let session = session.clone();
+            let app_tx = app_tx.clone();
+            let displayed = displayed.clone();
+            move |req| {
+                let session = session.clone();
+                let app_tx = app_tx.clone();
+                let displayed = displayed.clone();
+                Box::pin(async move {
+                    let request = AgentRequest::DisplayPasskey {
+                        passkey: req.passkey,
+                    };
+                    display(
+                        &session,
+                        &app_tx,
+                        &displayed,
+                        &req.adapter,
+                        req.device,
+                        request,
+                        req.cancel,
+                    )
+                    .await
+                })
+            }
+        })),
+        request_confirmation: Some(Box::new({
+            let session = session.clone();
+            let app_tx = app_tx.clone();
+            move |req| {
+                let session = session.clone();
+                let app_tx = app_tx.clone();
+                Box::pin(async move {
+                    let request = AgentRequest::RequestConfirmation {
+                        passkey: req.passkey,
+                    };
+                    match ask(&session, &app_tx, &req.adapter, req.device, request).await? {
+                        AgentReply::Accept => Ok(()),
+                        _ => Err(ReqError::Rejected),
+                    }
+                })
+            }
+        })),
+        authorize_service: Some(Box::new({
+            let session = session.clone();
+            let app_tx = app_tx.clone();
+            move |req| {
+                let session = session.clone();
+                let app_tx = app_tx.clone();
+                Box::pin(async move {
+                    let request = AgentRequest::AuthorizeService {
+                        service: req.service,
+                    };
+                    match ask(&session, &app_tx, &req.adapter, req.device, request).await? {
+                        AgentReply::Accept => Ok(()),
+                        _ => Err(ReqError::Rejected),
+                    }
+                })
+            }
+        })),
+        ..Default::default()
+    };
+
+    Ok(session.register_agent(agent).await?)
+}
@@ -1,21 +1,40 @@
+use std::collections::HashMap;
+
 use anyhow::Result;
+use bluer::Address;
+use log::debug;
 use tokio::sync::mpsc::{Receiver, Sender, channel};
 
 use crate::{
-    bluetooth::{Action, BTEvent, BTState},
+    agent::AgentPrompt,
+    bluetooth::{Action, BTError, BTEvent, BTFailure, BTState, DeviceOperation, Reply},
+    config,
+    control::ControlEvent,
+    notifications::{self, NotificationEvent},
+    prompt::{show_prompt, show_rename_prompt},
     tray::TrayEvent,
 };
 
 #[derive(Debug)]
 pub enum AppEvent {
     Request(Action),
+    /// Like `Request`, but the result is sent back once the action is done.
+    Call(Action, Reply),
     Response(BTState),
+    Prompt(AgentPrompt),
+    /// Asks for a new name for the device with the given address and current name.
+    RenamePrompt(Address, String),
+    Failure(BTFailure),
+    /// The bluetooth module is done with a request that had a `DeviceOperation`.
+    Finished(Address),
+    ConfigChanged,
     Shutdown,
 }
 
 #[derive(Debug)]
 pub struct App {
     state: BTState,
+    operations: HashMap<Address, DeviceOperation>,
     tx: Sender<AppEvent>,
     rx: Receiver<AppEvent>,
 }
@@ -23,27 +42,112 @@ impl App {
     pub fn new() -> Self {
         let (tx, rx) = channel::<AppEvent>(32);
         let state = BTState::default();
-        Self { tx, rx, state }
+        Self {
+            tx,
+            rx,
+            state,
+            operations: HashMap::new(),
+        }
+    }
+
+    /// The latest state along with the operations that are still running.
+    fn view(&self) -> BTState {
+        BTState {
+            operations: self.operations.clone(),
+            ..self.state.clone()
+        }
     }
 
     pub fn get_sender(&self) -> Sender<AppEvent> {
         self.tx.clone()
     }
 
-    pub async fn run(&mut self, tray_tx: Sender<TrayEvent>, bt_tx: Sender<BTEvent>) -> Result<()> {
+    async fn request(
+        &mut self,
+        action: Action,
+        reply: Option<Reply>,
+        tray_tx: &Sender<TrayEvent>,
+        bt_tx: &Sender<BTEvent>,
+    ) -> Result<()> {
+        if let Some((address, operation)) = action.operation() {
+            // A second click while the first one is still being handled.
+            if self.operations.contains_key(&address) {
+                debug!("Ignoring {action:?}, {address} is busy");
+
+                if let Some(reply) = reply {
+                    let name = self.state.adapter().map_or_else(
+                        || address.to_string(),
+                        |adapter| adapter.device_name(address),
+                    );
+                    let _ = reply.send(Err(BTFailure {
+                        operation: format!("{} {}", operation.verb(), name),
+                        error: BTError::InProgress,
+                    }));
+                }
+
+                return Ok(());
+            }
+
+            self.operations.insert(address, operation);
+            tray_tx.send(TrayEvent::Update(self.view())).await?;
+        }
+
+        bt_tx
+            .send(BTEvent::Request {
+                action,
+                state: self.state.clone(),
+                reply,
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn run(
+        &mut self,
+        tray_tx: Sender<TrayEvent>,
+        control_tx: Sender<ControlEvent>,
+        notification_tx: Sender<NotificationEvent>,
+        bt_tx: Sender<BTEvent>,
+    ) -> Result<()> {
         while let Some(event) = self.rx.recv().await {
             match event {
                 AppEvent::Request(action) => {
-                    bt_tx
-                        .send(BTEvent::Request {
-                            action,
-                            state: self.state.clone(),
-                        })
-                        .await?;
+                    self.request(action, None, &tray_tx, &bt_tx).await?;
+                }
+                AppEvent::Call(action, reply) => {
+                    self.request(action, Some(reply), &tray_tx, &bt_tx).await?;
                 }
                 AppEvent::Response(state) => {
+                    for notification in notifications::diff(&self.state, &state, &config::current())
+                    {
+                        notification_tx
+                            .send(NotificationEvent::Show(notification))
+                            .await?;
+                    }
+
                     self.state = state.clone();
-                    tray_tx.send(TrayEvent::Update(state)).await?;
+                    control_tx.send(ControlEvent::Update(state)).await?;
+                    tray_tx.send(TrayEvent::Update(self.view())).await?;
+                }
+                AppEvent::Finished(address) => {
+                    // No state is published after requests that didn't get as far as an adapter.
+                    self.operations.remove(&address);
+                    tray_tx.send(TrayEvent::Update(self.view())).await?;
+                }
+                AppEvent::Prompt(prompt) => {
+                    tokio::spawn(show_prompt(prompt));
+                }
+                AppEvent::RenamePrompt(address, name) => {
+                    tokio::spawn(show_rename_prompt(address, name, self.tx.clone()));
+                }
+                AppEvent::Failure(failure) => {
+                    tray_tx.send(TrayEvent::Failure(failure)).await?;
+                }
+                AppEvent::ConfigChanged => {
+                    // The menu layout and device options are read from the config while the
+                    // menu is built.
+                    tray_tx.send(TrayEvent::Update(self.view())).await?;
                 }
                 AppEvent::Shutdown => break,
             }
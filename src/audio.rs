@@ -0,0 +1,297 @@
+use std::{collections::HashMap, str::FromStr};
+
+use anyhow::{Result, bail};
+use bluer::Address;
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use tokio::process::Command;
+
+/// Sound servers name the cards of bluetooth devices after their address, e.g.
+/// `bluez_card.AA_BB_CC_DD_EE_FF`.
+const CARD_PREFIX: &str = "bluez_card.";
+
+/// Turns the card off. Disconnecting the device does that better.
+const OFF_PROFILE: &str = "off";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AudioProfile {
+    pub name: String,
+    pub description: String,
+}
+
+/// The sound card of a connected audio device.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AudioCard {
+    pub profiles: Vec<AudioProfile>,
+    pub active_profile: Option<String>,
+}
+
+/// Reads and sets card profiles on the sound server.
+pub trait AudioBackend: Send + Sync {
+    fn cards(&self) -> BoxFuture<'_, Result<HashMap<Address, AudioCard>>>;
+    fn set_profile<'a>(&'a self, address: Address, profile: &'a str) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Switches the card of the device to `profile`, if it has one by that name.
+pub async fn switch_profile(
+    backend: &dyn AudioBackend,
+    address: Address,
+    profile: &str,
+) -> Result<()> {
+    let cards = backend.cards().await?;
+
+    let Some(card) = cards.get(&address) else {
+        bail!("{} doesn't have a sound card", address);
+    };
+
+    if !card.profiles.iter().any(|p| p.name == profile) {
+        bail!("{} doesn't have a {} profile", address, profile);
+    }
+
+    if card.active_profile.as_deref() == Some(profile) {
+        return Ok(());
+    }
+
+    backend.set_profile(address, profile).await
+}
+
+fn card_name(address: Address) -> String {
+    format!("{}{}", CARD_PREFIX, address.to_string().replace(':', "_"))
+}
+
+/// Talks to PulseAudio, or PipeWire's PulseAudio server, through `pactl`.
+#[derive(Debug, Default)]
+pub struct Pactl;
+
+#[derive(Debug, Deserialize)]
+struct PactlCard {
+    name: String,
+    active_profile: Option<String>,
+    #[serde(default)]
+    profiles: HashMap<String, PactlProfile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PactlProfile {
+    description: String,
+    #[serde(default)]
+    priority: u32,
+    #[serde(default = "available")]
+    available: bool,
+}
+
+fn available() -> bool {
+    true
+}
+
+/// Parses the output of `pactl --format=json list cards`, keeping bluetooth cards only.
+fn parse_cards(output: &str) -> Result<HashMap<Address, AudioCard>> {
+    let cards = serde_json::from_str::<Vec<PactlCard>>(output)?;
+
+    Ok(cards
+        .into_iter()
+        .filter_map(|card| {
+            let address = card.name.strip_prefix(CARD_PREFIX)?.replace('_', ":");
+            let address = Address::from_str(&address).ok()?;
+
+            let mut profiles = card
+                .profiles
+                .into_iter()
+                .filter(|(name, profile)| name != OFF_PROFILE && profile.available)
+                .collect::<Vec<_>>();
+
+            // Same order as pavucontrol, best quality first.
+            profiles.sort_by(|(a_name, a), (b_name, b)| {
+                b.priority.cmp(&a.priority).then(a_name.cmp(b_name))
+            });
+
+            Some((
+                address,
+                AudioCard {
+                    profiles: profiles
+                        .into_iter()
+                        .map(|(name, profile)| AudioProfile {
+                            name,
+                            description: profile.description,
+                        })
+                        .collect(),
+                    active_profile: card.active_profile.filter(|name| name != OFF_PROFILE),
+                },
+            ))
+        })
+        .collect())
+}
+
+impl AudioBackend for Pactl {
+    fn cards(&self) -> BoxFuture<'_, Result<HashMap<Address, AudioCard>>> {
+        Box::pin(async {
+            let output = Command::new("pactl")
+                .args(["--format=json", "list", "cards"])
+                .output()
+                .await?;
+
+            if !output.status.success() {
+                bail!(
+                    "pactl failed to list cards: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+
+            parse_cards(&String::from_utf8_lossy(&output.stdout))
+        })
+    }
+
+    fn set_profile<'a>(&'a self, address: Address, profile: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let output = Command::new("pactl")
+                .arg("set-card-profile")
+                .arg(card_name(address))
+                .arg(profile)
+                .output()
+                .await?;
+
+            if !output.status.success() {
+                bail!(
+                    "pactl failed to set the {} profile: {}",
+                    profile,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+
+            Ok(())
+        })
+    }
+}
+
+/// Keeps cards in memory so that tests don't need a sound server.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct FakeAudio {
+    pub cards: std::sync::Mutex<HashMap<Address, AudioCard>>,
+    pub profile_changes: std::sync::Mutex<Vec<(Address, String)>>,
+    /// How many times the cards have been listed.
+    pub listings: std::sync::Mutex<usize>,
+}
+
+#[cfg(test)]
+impl AudioBackend for FakeAudio {
+    fn cards(&self) -> BoxFuture<'_, Result<HashMap<Address, AudioCard>>> {
+        Box::pin(async {
+            *self.listings.lock().unwrap() += 1;
+            Ok(self.cards.lock().unwrap().clone())
+        })
+    }
+
+    fn set_profile<'a>(&'a self, address: Address, profile: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            if let Some(card) = self.cards.lock().unwrap().get_mut(&address) {
+                card.active_profile = Some(profile.to_string());
+            }
+
+            self.profile_changes
+                .lock()
+                .unwrap()
+                .push((address, profile.to_string()));
+
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: Address = Address::new([0x00, 0x1b, 0x66, 0xaa, 0xbb, 0xcc]);
+
+    // Trimmed down from PipeWire 1.0 with a pair of headphones connected.
+    const PACTL_CARDS: &str = r#"[
+        {
+            "index": 42,
+            "name": "alsa_card.pci-0000_00_1f.3",
+            "active_profile": "output:analog-stereo",
+            "profiles": {
+                "output:analog-stereo": { "description": "Analog Stereo Output", "sinks": 1, "sources": 0, "priority": 6500, "available": true }
+            }
+        },
+        {
+            "index": 57,
+            "name": "bluez_card.00_1B_66_AA_BB_CC",
+            "driver": "module-bluez5-device.c",
+            "active_profile": "a2dp-sink",
+            "profiles": {
+                "off": { "description": "Off", "sinks": 0, "sources": 0, "priority": 0, "available": true },
+                "a2dp-sink": { "description": "High Fidelity Playback (A2DP Sink)", "sinks": 1, "sources": 0, "priority": 40, "available": true },
+                "headset-head-unit": { "description": "Headset Head Unit (HSP/HFP)", "sinks": 1, "sources": 1, "priority": 30, "available": true },
+                "a2dp-sink-aac": { "description": "High Fidelity Playback (A2DP Sink, codec AAC)", "sinks": 1, "sources": 0, "priority": 39, "available": false }
+            }
+        }
+    ]"#;
+
+    fn fake() -> FakeAudio {
+        let fake = FakeAudio::default();
+        let cards = parse_cards(PACTL_CARDS).unwrap();
+        *fake.cards.lock().unwrap() = cards;
+        fake
+    }
+
+    #[test]
+    fn pactl_output_is_parsed() {
+        let cards = parse_cards(PACTL_CARDS).unwrap();
+
+        assert_eq!(cards.len(), 1);
+
+        let card = &cards[&ADDRESS];
+        assert_eq!(card.active_profile.as_deref(), Some("a2dp-sink"));
+        assert_eq!(
+            card.profiles
+                .iter()
+                .map(|profile| profile.name.as_str())
+                .collect::<Vec<_>>(),
+            ["a2dp-sink", "headset-head-unit"]
+        );
+        assert_eq!(card_name(ADDRESS), "bluez_card.00_1B_66_AA_BB_CC");
+    }
+
+    #[tokio::test]
+    async fn switching_profiles() {
+        let fake = fake();
+
+        switch_profile(&fake, ADDRESS, "headset-head-unit")
+            .await
+            .unwrap();
+        // Already active, so the sound server isn't bothered again.
+        switch_profile(&fake, ADDRESS, "headset-head-unit")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *fake.profile_changes.lock().unwrap(),
+            [(ADDRESS, "headset-head-unit".to_string())]
+        );
+        assert_eq!(
+            fake.cards().await.unwrap()[&ADDRESS]
+                .active_profile
+                .as_deref(),
+            Some("headset-head-unit")
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_profiles_and_cards_are_rejected() {
+        let fake = fake();
+
+        assert!(switch_profile(&fake, ADDRESS, "off").await.is_err());
+        assert!(
+            switch_profile(&fake, ADDRESS, "a2dp-sink-aac")
+                .await
+                .is_err()
+        );
+        assert!(
+            switch_profile(&fake, Address::any(), "a2dp-sink")
+                .await
+                .is_err()
+        );
+        assert!(fake.profile_changes.lock().unwrap().is_empty());
+    }
+}
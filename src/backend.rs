@@ -0,0 +1,953 @@
+use std::io;
+
+use bluer::{AdapterEvent, Address, DeviceEvent, Session, SessionEvent};
+use futures::{StreamExt, future::BoxFuture, stream::BoxStream};
+use tokio::sync::mpsc::Sender;
+
+use crate::{
+    bluetooth::BTDevice,
+    rfkill::{self, Rfkill},
+};
+
+/// Everything the applet asks of BlueZ and rfkill. Adapters are referred to by name, e.g. `hci0`.
+pub trait BluetoothBackend: Send + Sync {
+    fn adapter_names(&self) -> BoxFuture<'_, bluer::Result<Vec<String>>>;
+    fn default_adapter(&self) -> BoxFuture<'_, bluer::Result<String>>;
+    /// Adapters being plugged in and out.
+    fn events(&self) -> BoxFuture<'_, bluer::Result<BoxStream<'static, SessionEvent>>>;
+
+    fn alias<'a>(&'a self, adapter: &'a str) -> BoxFuture<'a, bluer::Result<String>>;
+    fn is_powered<'a>(&'a self, adapter: &'a str) -> BoxFuture<'a, bluer::Result<bool>>;
+    fn set_powered<'a>(
+        &'a self,
+        adapter: &'a str,
+        powered: bool,
+    ) -> BoxFuture<'a, bluer::Result<()>>;
+    fn is_discovering<'a>(&'a self, adapter: &'a str) -> BoxFuture<'a, bluer::Result<bool>>;
+    fn is_discoverable<'a>(&'a self, adapter: &'a str) -> BoxFuture<'a, bluer::Result<bool>>;
+    fn is_pairable<'a>(&'a self, adapter: &'a str) -> BoxFuture<'a, bluer::Result<bool>>;
+    /// In seconds, 0 when discoverable mode stays on until it's turned off.
+    fn discoverable_timeout<'a>(&'a self, adapter: &'a str) -> BoxFuture<'a, bluer::Result<u32>>;
+    /// In seconds, 0 when pairable mode stays on until it's turned off.
+    fn pairable_timeout<'a>(&'a self, adapter: &'a str) -> BoxFuture<'a, bluer::Result<u32>>;
+    /// The timeout starts when discoverable mode is turned on. It's stored on the adapter, so it
+    /// also applies when something else turns the mode on, even after we're gone.
+    fn set_discoverable<'a>(
+        &'a self,
+        adapter: &'a str,
+        discoverable: bool,
+        timeout_secs: u32,
+    ) -> BoxFuture<'a, bluer::Result<()>>;
+    /// The timeout starts when pairable mode is turned on, and is stored on the adapter as well.
+    fn set_pairable<'a>(
+        &'a self,
+        adapter: &'a str,
+        pairable: bool,
+        timeout_secs: u32,
+    ) -> BoxFuture<'a, bluer::Result<()>>;
+    fn adapter_events<'a>(
+        &'a self,
+        adapter: &'a str,
+    ) -> BoxFuture<'a, bluer::Result<BoxStream<'static, AdapterEvent>>>;
+    /// Discovery runs for as long as the returned stream is alive.
+    fn discover_devices<'a>(
+        &'a self,
+        adapter: &'a str,
+    ) -> BoxFuture<'a, bluer::Result<BoxStream<'static, AdapterEvent>>>;
+    fn device_addresses<'a>(
+        &'a self,
+        adapter: &'a str,
+    ) -> BoxFuture<'a, bluer::Result<Vec<Address>>>;
+    fn remove_device<'a>(
+        &'a self,
+        adapter: &'a str,
+        address: Address,
+    ) -> BoxFuture<'a, bluer::Result<()>>;
+
+    fn device<'a>(
+        &'a self,
+        adapter: &'a str,
+        address: Address,
+    ) -> BoxFuture<'a, bluer::Result<BTDevice>>;
+    fn device_events<'a>(
+        &'a self,
+        adapter: &'a str,
+        address: Address,
+    ) -> BoxFuture<'a, bluer::Result<BoxStream<'static, DeviceEvent>>>;
+    fn is_connected<'a>(
+        &'a self,
+        adapter: &'a str,
+        address: Address,
+    ) -> BoxFuture<'a, bluer::Result<bool>>;
+    fn connect<'a>(
+        &'a self,
+        adapter: &'a str,
+        address: Address,
+    ) -> BoxFuture<'a, bluer::Result<()>>;
+    fn disconnect<'a>(
+        &'a self,
+        adapter: &'a str,
+        address: Address,
+    ) -> BoxFuture<'a, bluer::Result<()>>;
+    fn pair<'a>(&'a self, adapter: &'a str, address: Address) -> BoxFuture<'a, bluer::Result<()>>;
+    fn set_trusted<'a>(
+        &'a self,
+        adapter: &'a str,
+        address: Address,
+        trusted: bool,
+    ) -> BoxFuture<'a, bluer::Result<()>>;
+    fn set_blocked<'a>(
+        &'a self,
+        adapter: &'a str,
+        address: Address,
+        blocked: bool,
+    ) -> BoxFuture<'a, bluer::Result<()>>;
+    /// An empty alias makes BlueZ go back to the name the device advertises.
+    fn set_alias<'a>(
+        &'a self,
+        adapter: &'a str,
+        address: Address,
+        alias: String,
+    ) -> BoxFuture<'a, bluer::Result<()>>;
+
+    /// The current rfkill state of every radio.
+    fn rfkill(&self) -> BoxFuture<'_, io::Result<Rfkill>>;
+    /// Sends every change to the rfkill state from here on.
+    fn watch_rfkill(&self, tx: Sender<Rfkill>) -> io::Result<()>;
+    fn set_soft_blocked<'a>(
+        &'a self,
+        adapter: &'a str,
+        blocked: bool,
+    ) -> BoxFuture<'a, io::Result<()>>;
+    /// Soft blocks or unblocks every radio, bluetooth or not.
+    fn set_all_soft_blocked(&self, blocked: bool) -> BoxFuture<'_, io::Result<()>>;
+}
+
+/// Talks to bluetoothd over D-Bus, and to the kernel for rfkill.
+#[derive(Debug, Clone)]
+pub struct Bluer {
+    session: Session,
+}
+
+impl Bluer {
+    pub fn new(session: Session) -> Self {
+        Self { session }
+    }
+
+    fn device(&self, adapter: &str, address: Address) -> bluer::Result<bluer::Device> {
+        self.session.adapter(adapter)?.device(address)
+    }
+}
+
+impl BluetoothBackend for Bluer {
+    fn adapter_names(&self) -> BoxFuture<'_, bluer::Result<Vec<String>>> {
+        Box::pin(self.session.adapter_names())
+    }
+
+    fn default_adapter(&self) -> BoxFuture<'_, bluer::Result<String>> {
+        Box::pin(async {
+            let adapter = self.session.default_adapter().await?;
+            Ok(adapter.name().to_string())
+        })
+    }
+
+    fn events(&self) -> BoxFuture<'_, bluer::Result<BoxStream<'static, SessionEvent>>> {
+        Box::pin(async { Ok(self.session.events().await?.boxed()) })
+    }
+
+    fn alias<'a>(&'a self, adapter: &'a str) -> BoxFuture<'a, bluer::Result<String>> {
+        Box::pin(async move { self.session.adapter(adapter)?.alias().await })
+    }
+
+    fn is_powered<'a>(&'a self, adapter: &'a str) -> BoxFuture<'a, bluer::Result<bool>> {
+        Box::pin(async move { self.session.adapter(adapter)?.is_powered().await })
+    }
+
+    fn set_powered<'a>(
+        &'a self,
+        adapter: &'a str,
+        powered: bool,
+    ) -> BoxFuture<'a, bluer::Result<()>> {
+        Box::pin(async move { self.session.adapter(adapter)?.set_powered(powered).await })
+    }
+
+    fn is_discovering<'a>(&'a self, adapter: &'a str) -> BoxFuture<'a, bluer::Result<bool>> {
+        Box::pin(async move { self.session.adapter(adapter)?.is_discovering().await })
+    }
+
+    fn is_discoverable<'a>(&'a self, adapter: &'a str) -> BoxFuture<'a, bluer::Result<bool>> {
+        Box::pin(async move { self.session.adapter(adapter)?.is_discoverable().await })
+    }
+
+    fn is_pairable<'a>(&'a self, adapter: &'a str) -> BoxFuture<'a, bluer::Result<bool>> {
+        Box::pin(async move { self.session.adapter(adapter)?.is_pairable().await })
+    }
+
+    fn discoverable_timeout<'a>(&'a self, adapter: &'a str) -> BoxFuture<'a, bluer::Result<u32>> {
+        Box::pin(async move { self.session.adapter(adapter)?.discoverable_timeout().await })
+    }
+
+    fn pairable_timeout<'a>(&'a self, adapter: &'a str) -> BoxFuture<'a, bluer::Result<u32>> {
+        Box::pin(async move { self.session.adapter(adapter)?.pairable_timeout().await })
+    }
+
+    fn set_discoverable<'a>(
+        &'a self,
+        adapter: &'a str,
+        discoverable: bool,
+        timeout_secs: u32,
+    ) -> BoxFuture<'a, bluer::Result<()>> {
+        Box::pin(async move {
+            let adapter = self.session.adapter(adapter)?;
+
+            if discoverable {
+                adapter.set_discoverable_timeout(timeout_secs).await?;
+            }
+
+            adapter.set_discoverable(discoverable).await
+        })
+    }
+
+    fn set_pairable<'a>(
+        &'a self,
+        adapter: &'a str,
+        pairable: bool,
+        timeout_secs: u32,
+    ) -> BoxFuture<'a, bluer::Result<()>> {
+        Box::pin(async move {
+            let adapter = self.session.adapter(adapter)?;
+
+            if pairable {
+                adapter.set_pairable_timeout(timeout_secs).await?;
+            }
+
+            adapter.set_pairable(pairable).await
+        })
+    }
+
+    fn adapter_events<'a>(
+        &'a self,
+        adapter: &'a str,
+    ) -> BoxFuture<'a, bluer::Result<BoxStream<'static, AdapterEvent>>> {
+        Box::pin(async move { Ok(self.session.adapter(adapter)?.events().await?.boxed()) })
+    }
+
+    fn discover_devices<'a>(
+        &'a self,
+        adapter: &'a str,
+    ) -> BoxFuture<'a, bluer::Result<BoxStream<'static, AdapterEvent>>> {
+        Box::pin(async move {
+            Ok(self
+                .session
+                .adapter(adapter)?
+                .discover_devices()
+                .await?
+                .boxed())
+        })
+    }
+
+    fn device_addresses<'a>(
+        &'a self,
+        adapter: &'a str,
+    ) -> BoxFuture<'a, bluer::Result<Vec<Address>>> {
+        Box::pin(async move { self.session.adapter(adapter)?.device_addresses().await })
+    }
+
+    fn remove_device<'a>(
+        &'a self,
+        adapter: &'a str,
+        address: Address,
+    ) -> BoxFuture<'a, bluer::Result<()>> {
+        Box::pin(async move { self.session.adapter(adapter)?.remove_device(address).await })
+    }
+
+    fn device<'a>(
+        &'a self,
+        adapter: &'a str,
+        address: Address,
+    ) -> BoxFuture<'a, bluer::Result<BTDevice>> {
+        Box::pin(async move {
+            let device = self.device(adapter, address)?;
+            Ok(BTDevice::from_device(&device).await)
+        })
+    }
+
+    fn device_events<'a>(
+        &'a self,
+        adapter: &'a str,
+        address: Address,
+    ) -> BoxFuture<'a, bluer::Result<BoxStream<'static, DeviceEvent>>> {
+        Box::pin(async move { Ok(self.device(adapter, address)?.events().await?.boxed()) })
+    }
+
+    fn is_connected<'a>(
+        &'a self,
+        adapter: &'a str,
+        address: Address,
+    ) -> BoxFuture<'a, bluer::Result<bool>> {
+        Box::pin(async move { self.device(adapter, address)?.is_connected().await })
+    }
+
+    fn connect<'a>(
+        &'a self,
+        adapter: &'a str,
+        address: Address,
+    ) -> BoxFuture<'a, bluer::Result<()>> {
+        Box::pin(async move { self.device(adapter, address)?.connect().await })
+    }
+
+    fn disconnect<'a>(
+        &'a self,
+        adapter: &'a str,
+        address: Address,
+    ) -> BoxFuture<'a, bluer::Result<()>> {
+        Box::pin(async move { self.device(adapter, address)?.disconnect().await })
+    }
+
+    fn pair<'a>(&'a self, adapter: &'a str, address: Address) -> BoxFuture<'a, bluer::Result<()>> {
+        Box::pin(async move { self.device(adapter, address)?.pair().await })
+    }
+
+    fn set_trusted<'a>(
+        &'a self,
+        adapter: &'a str,
+        address: Address,
+        trusted: bool,
+    ) -> BoxFuture<'a, bluer::Result<()>> {
+        Box::pin(async move { self.device(adapter, address)?.set_trusted(trusted).await })
+    }
+
+    fn set_blocked<'a>(
+        &'a self,
+        adapter: &'a str,
+        address: Address,
+        blocked: bool,
+    ) -> BoxFuture<'a, bluer::Result<()>> {
+        Box::pin(async move { self.device(adapter, address)?.set_blocked(blocked).await })
+    }
+
+    fn set_alias<'a>(
+        &'a self,
+        adapter: &'a str,
+        address: Address,
+        alias: String,
+    ) -> BoxFuture<'a, bluer::Result<()>> {
+        Box::pin(async move { self.device(adapter, address)?.set_alias(alias).await })
+    }
+
+    fn rfkill(&self) -> BoxFuture<'_, io::Result<Rfkill>> {
+        Box::pin(blocking(Rfkill::load))
+    }
+
+    fn watch_rfkill(&self, tx: Sender<Rfkill>) -> io::Result<()> {
+        rfkill::watch(tx)
+    }
+
+    fn set_soft_blocked<'a>(
+        &'a self,
+        adapter: &'a str,
+        blocked: bool,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        let adapter = adapter.to_string();
+        Box::pin(blocking(move || rfkill::set_soft_block(&adapter, blocked)))
+    }
+
+    fn set_all_soft_blocked(&self, blocked: bool) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(blocking(move || rfkill::set_all_soft_blocked(blocked)))
+    }
+}
+
+/// Runs a read or write of `/dev/rfkill` where it can't hold up the runtime.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// A scriptable stand-in for BlueZ and rfkill, so that tests don't need an adapter.
+#[cfg(test)]
+pub mod fake {
+    use std::{
+        collections::{BTreeMap, HashMap, HashSet},
+        sync::Mutex,
+    };
+
+    use bluer::{AdapterProperty, DeviceProperty, ErrorKind};
+    use futures::channel::mpsc::{UnboundedSender, unbounded};
+
+    use super::*;
+    use crate::rfkill::{RfkillEvent, RfkillOp, RfkillType};
+
+    #[derive(Default)]
+    struct FakeAdapter {
+        alias: String,
+        powered: bool,
+        discovering: bool,
+        discoverable: bool,
+        pairable: bool,
+        discoverable_timeout: u32,
+        pairable_timeout: u32,
+        devices: BTreeMap<Address, BTDevice>,
+        events: Vec<UnboundedSender<AdapterEvent>>,
+        device_events: HashMap<Address, Vec<UnboundedSender<DeviceEvent>>>,
+    }
+
+    impl FakeAdapter {
+        fn emit(&mut self, event: AdapterEvent) {
+            self.events
+                .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+        }
+
+        /// Changes the device the way BlueZ would and tells whoever is listening.
+        fn change_device(&mut self, address: Address, property: DeviceProperty) {
+            if let Some(device) = self.devices.get_mut(&address) {
+                device.apply(property.clone());
+            }
+
+            if let Some(txs) = self.device_events.get_mut(&address) {
+                let event = DeviceEvent::PropertyChanged(property);
+                txs.retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct FakeState {
+        adapters: BTreeMap<String, FakeAdapter>,
+        rfkill: Rfkill,
+        rfkill_tx: Option<Sender<Rfkill>>,
+        /// Returned by the next call of that name, e.g. "connect", instead of carrying it out.
+        failures: HashMap<&'static str, bluer::Error>,
+        /// Calls that never finish, like BlueZ waiting on a device that doesn't answer.
+        stalled: HashSet<&'static str>,
+        calls: Vec<String>,
+        reads: HashMap<&'static str, usize>,
+    }
+
+    #[derive(Default)]
+    pub struct FakeBluetooth {
+        state: Mutex<FakeState>,
+    }
+
+    fn missing(what: impl std::fmt::Display) -> bluer::Error {
+        bluer::Error {
+            kind: ErrorKind::DoesNotExist,
+            message: format!("{} does not exist", what),
+        }
+    }
+
+    impl FakeBluetooth {
+        pub fn add_adapter(&self, name: &str, powered: bool) {
+            self.state.lock().unwrap().adapters.insert(
+                name.to_string(),
+                FakeAdapter {
+                    alias: format!("{} alias", name),
+                    powered,
+                    ..Default::default()
+                },
+            );
+        }
+
+        pub fn add_device(&self, adapter: &str, device: BTDevice) {
+            let mut state = self.state.lock().unwrap();
+            let adapter = state.adapters.get_mut(adapter).unwrap();
+            let address = device.address;
+
+            adapter.devices.insert(address, device);
+            adapter.emit(AdapterEvent::DeviceAdded(address));
+        }
+
+        /// Makes the next call named `call` fail with `error`.
+        pub fn fail(&self, call: &'static str, error: bluer::Error) {
+            self.state.lock().unwrap().failures.insert(call, error);
+        }
+
+        /// Makes every call named `call` hang without doing anything.
+        pub fn stall(&self, call: &'static str) {
+            self.state.lock().unwrap().stalled.insert(call);
+        }
+
+        fn is_stalled(&self, call: &str) -> bool {
+            self.state.lock().unwrap().stalled.contains(call)
+        }
+
+        /// The calls made to change something, e.g. "connect 00:00:00:00:00:01", in order. Failed
+        /// calls are included.
+        pub fn calls(&self) -> Vec<String> {
+            self.state.lock().unwrap().calls.clone()
+        }
+
+        /// How many times `call`, e.g. "device", has read something so far.
+        pub fn reads(&self, call: &str) -> usize {
+            self.state
+                .lock()
+                .unwrap()
+                .reads
+                .get(call)
+                .copied()
+                .unwrap_or_default()
+        }
+
+        /// How many listeners for the property changes of a device are still around.
+        pub fn device_listeners(&self, adapter: &str, address: Address) -> usize {
+            self.state.lock().unwrap().adapters[adapter]
+                .device_events
+                .get(&address)
+                .map_or(0, |txs| txs.iter().filter(|tx| !tx.is_closed()).count())
+        }
+
+        /// Reports a property change on a device, as if the device did something by itself.
+        pub fn change_device(&self, adapter: &str, address: Address, property: DeviceProperty) {
+            let mut state = self.state.lock().unwrap();
+            state
+                .adapters
+                .get_mut(adapter)
+                .unwrap()
+                .change_device(address, property);
+        }
+
+        /// Sets the rfkill state of the adapter, as if a switch was flipped.
+        pub fn set_rfkill(&self, adapter: &str, soft: bool, hard: bool) {
+            let mut state = self.state.lock().unwrap();
+            let idx = state
+                .adapters
+                .keys()
+                .position(|name| name == adapter)
+                .unwrap();
+            let event = RfkillEvent {
+                idx: idx as u32,
+                kind: RfkillType::Bluetooth,
+                op: RfkillOp::Change,
+                soft,
+                hard,
+            };
+
+            state.rfkill.apply(&event, |_| adapter.to_string());
+
+            let rfkill = state.rfkill.clone();
+            if let Some(tx) = &state.rfkill_tx {
+                let _ = tx.try_send(rfkill);
+            }
+        }
+
+        /// Records a call that changes something on `adapter`, failing it if a failure was
+        /// scripted.
+        fn call(
+            &self,
+            call: &'static str,
+            adapter: &str,
+            detail: impl std::fmt::Display,
+            f: impl FnOnce(&mut FakeAdapter) -> bluer::Result<()>,
+        ) -> bluer::Result<()> {
+            let mut state = self.state.lock().unwrap();
+            state.calls.push(format!("{} {}", call, detail));
+
+            if let Some(error) = state.failures.remove(call) {
+                return Err(error);
+            }
+
+            f(state
+                .adapters
+                .get_mut(adapter)
+                .ok_or_else(|| missing(adapter))?)
+        }
+
+        /// Counts a call that only reads something from `adapter`.
+        fn read<T>(
+            &self,
+            call: &'static str,
+            adapter: &str,
+            f: impl FnOnce(&FakeAdapter) -> bluer::Result<T>,
+        ) -> bluer::Result<T> {
+            let mut state = self.state.lock().unwrap();
+            *state.reads.entry(call).or_default() += 1;
+            f(state
+                .adapters
+                .get(adapter)
+                .ok_or_else(|| missing(adapter))?)
+        }
+
+        fn soft_block(&self, adapter: &str, blocked: bool) {
+            let hard = {
+                let mut state = self.state.lock().unwrap();
+                state
+                    .calls
+                    .push(format!("set_soft_blocked {} {}", adapter, blocked));
+                state
+                    .rfkill
+                    .device(adapter)
+                    .is_some_and(|device| device.hard)
+            };
+
+            self.set_rfkill(adapter, blocked, hard);
+        }
+
+        fn call_device(
+            &self,
+            call: &'static str,
+            adapter: &str,
+            address: Address,
+            property: DeviceProperty,
+        ) -> bluer::Result<()> {
+            self.call(call, adapter, address, |adapter| {
+                if !adapter.devices.contains_key(&address) {
+                    return Err(missing(address));
+                }
+
+                adapter.change_device(address, property);
+                Ok(())
+            })
+        }
+    }
+
+    impl BluetoothBackend for FakeBluetooth {
+        fn adapter_names(&self) -> BoxFuture<'_, bluer::Result<Vec<String>>> {
+            let names = self
+                .state
+                .lock()
+                .unwrap()
+                .adapters
+                .keys()
+                .cloned()
+                .collect();
+            Box::pin(async { Ok(names) })
+        }
+
+        fn default_adapter(&self) -> BoxFuture<'_, bluer::Result<String>> {
+            let name = self.state.lock().unwrap().adapters.keys().next().cloned();
+            Box::pin(async { name.ok_or_else(|| missing("default adapter")) })
+        }
+
+        fn events(&self) -> BoxFuture<'_, bluer::Result<BoxStream<'static, SessionEvent>>> {
+            // Adapters are only ever set up before the fake is handed out.
+            Box::pin(async { Ok(futures::stream::pending().boxed()) })
+        }
+
+        fn alias<'a>(&'a self, adapter: &'a str) -> BoxFuture<'a, bluer::Result<String>> {
+            let alias = self.read("alias", adapter, |adapter| Ok(adapter.alias.clone()));
+            Box::pin(async { alias })
+        }
+
+        fn is_powered<'a>(&'a self, adapter: &'a str) -> BoxFuture<'a, bluer::Result<bool>> {
+            let powered = self.read("is_powered", adapter, |adapter| Ok(adapter.powered));
+            Box::pin(async { powered })
+        }
+
+        fn set_powered<'a>(
+            &'a self,
+            adapter: &'a str,
+            powered: bool,
+        ) -> BoxFuture<'a, bluer::Result<()>> {
+            let result = self.call("set_powered", adapter, powered, |adapter| {
+                adapter.powered = powered;
+                adapter.emit(AdapterEvent::PropertyChanged(AdapterProperty::Powered(
+                    powered,
+                )));
+                Ok(())
+            });
+            Box::pin(async { result })
+        }
+
+        fn is_discovering<'a>(&'a self, adapter: &'a str) -> BoxFuture<'a, bluer::Result<bool>> {
+            let discovering =
+                self.read("is_discovering", adapter, |adapter| Ok(adapter.discovering));
+            Box::pin(async { discovering })
+        }
+
+        fn is_discoverable<'a>(&'a self, adapter: &'a str) -> BoxFuture<'a, bluer::Result<bool>> {
+            let discoverable = self.read("is_discoverable", adapter, |adapter| {
+                Ok(adapter.discoverable)
+            });
+            Box::pin(async { discoverable })
+        }
+
+        fn is_pairable<'a>(&'a self, adapter: &'a str) -> BoxFuture<'a, bluer::Result<bool>> {
+            let pairable = self.read("is_pairable", adapter, |adapter| Ok(adapter.pairable));
+            Box::pin(async { pairable })
+        }
+
+        fn discoverable_timeout<'a>(
+            &'a self,
+            adapter: &'a str,
+        ) -> BoxFuture<'a, bluer::Result<u32>> {
+            let timeout = self.read("discoverable_timeout", adapter, |adapter| {
+                Ok(adapter.discoverable_timeout)
+            });
+            Box::pin(async { timeout })
+        }
+
+        fn pairable_timeout<'a>(&'a self, adapter: &'a str) -> BoxFuture<'a, bluer::Result<u32>> {
+            let timeout = self.read("pairable_timeout", adapter, |adapter| {
+                Ok(adapter.pairable_timeout)
+            });
+            Box::pin(async { timeout })
+        }
+
+        fn set_discoverable<'a>(
+            &'a self,
+            adapter: &'a str,
+            discoverable: bool,
+            timeout_secs: u32,
+        ) -> BoxFuture<'a, bluer::Result<()>> {
+            let detail = format!("{} {}", discoverable, timeout_secs);
+            let result = self.call("set_discoverable", adapter, detail, |adapter| {
+                if discoverable && adapter.discoverable_timeout != timeout_secs {
+                    adapter.discoverable_timeout = timeout_secs;
+                    adapter.emit(AdapterEvent::PropertyChanged(
+                        AdapterProperty::DiscoverableTimeout(timeout_secs),
+                    ));
+                }
+
+                adapter.discoverable = discoverable;
+                adapter.emit(AdapterEvent::PropertyChanged(
+                    AdapterProperty::Discoverable(discoverable),
+                ));
+                Ok(())
+            });
+            Box::pin(async { result })
+        }
+
+        fn set_pairable<'a>(
+            &'a self,
+            adapter: &'a str,
+            pairable: bool,
+            timeout_secs: u32,
+        ) -> BoxFuture<'a, bluer::Result<()>> {
+            let detail = format!("{} {}", pairable, timeout_secs);
+            let result = self.call("set_pairable", adapter, detail, |adapter| {
+                if pairable && adapter.pairable_timeout != timeout_secs {
+                    adapter.pairable_timeout = timeout_secs;
+                    adapter.emit(AdapterEvent::PropertyChanged(
+                        AdapterProperty::PairableTimeout(timeout_secs),
+                    ));
+                }
+
+                adapter.pairable = pairable;
+                adapter.emit(AdapterEvent::PropertyChanged(AdapterProperty::Pairable(
+                    pairable,
+                )));
+                Ok(())
+            });
+            Box::pin(async { result })
+        }
+
+        fn adapter_events<'a>(
+            &'a self,
+            adapter: &'a str,
+        ) -> BoxFuture<'a, bluer::Result<BoxStream<'static, AdapterEvent>>> {
+            let (tx, rx) = unbounded();
+            let mut state = self.state.lock().unwrap();
+            let result = match state.adapters.get_mut(adapter) {
+                Some(adapter) => {
+                    adapter.events.push(tx);
+                    Ok(rx.boxed())
+                }
+                None => Err(missing(adapter)),
+            };
+            Box::pin(async { result })
+        }
+
+        fn discover_devices<'a>(
+            &'a self,
+            adapter: &'a str,
+        ) -> BoxFuture<'a, bluer::Result<BoxStream<'static, AdapterEvent>>> {
+            let result = self.call("discover_devices", adapter, adapter, |adapter| {
+                adapter.discovering = true;
+                adapter.emit(AdapterEvent::PropertyChanged(AdapterProperty::Discovering(
+                    true,
+                )));
+                Ok(())
+            });
+            Box::pin(async { result.map(|()| futures::stream::pending().boxed()) })
+        }
+
+        fn device_addresses<'a>(
+            &'a self,
+            adapter: &'a str,
+        ) -> BoxFuture<'a, bluer::Result<Vec<Address>>> {
+            let addresses = self.read("device_addresses", adapter, |adapter| {
+                Ok(adapter.devices.keys().copied().collect())
+            });
+            Box::pin(async { addresses })
+        }
+
+        fn remove_device<'a>(
+            &'a self,
+            adapter: &'a str,
+            address: Address,
+        ) -> BoxFuture<'a, bluer::Result<()>> {
+            let result = self.call("remove_device", adapter, address, |adapter| {
+                adapter
+                    .devices
+                    .remove(&address)
+                    .ok_or_else(|| missing(address))?;
+                adapter.device_events.remove(&address);
+                adapter.emit(AdapterEvent::DeviceRemoved(address));
+                Ok(())
+            });
+            Box::pin(async { result })
+        }
+
+        fn device<'a>(
+            &'a self,
+            adapter: &'a str,
+            address: Address,
+        ) -> BoxFuture<'a, bluer::Result<BTDevice>> {
+            let device = self.read("device", adapter, |adapter| {
+                adapter
+                    .devices
+                    .get(&address)
+                    .cloned()
+                    .ok_or_else(|| missing(address))
+            });
+            Box::pin(async { device })
+        }
+
+        fn device_events<'a>(
+            &'a self,
+            adapter: &'a str,
+            address: Address,
+        ) -> BoxFuture<'a, bluer::Result<BoxStream<'static, DeviceEvent>>> {
+            let (tx, rx) = unbounded();
+            let mut state = self.state.lock().unwrap();
+            let result = match state.adapters.get_mut(adapter) {
+                Some(adapter) => {
+                    adapter.device_events.entry(address).or_default().push(tx);
+                    Ok(rx.boxed())
+                }
+                None => Err(missing(adapter)),
+            };
+            Box::pin(async { result })
+        }
+
+        fn is_connected<'a>(
+            &'a self,
+            adapter: &'a str,
+            address: Address,
+        ) -> BoxFuture<'a, bluer::Result<bool>> {
+            let connected = self.read("is_connected", adapter, |adapter| {
+                adapter
+                    .devices
+                    .get(&address)
+                    .map(BTDevice::is_on)
+                    .ok_or_else(|| missing(address))
+            });
+            Box::pin(async { connected })
+        }
+
+        fn connect<'a>(
+            &'a self,
+            adapter: &'a str,
+            address: Address,
+        ) -> BoxFuture<'a, bluer::Result<()>> {
+            if self.is_stalled("connect") {
+                return Box::pin(futures::future::pending());
+            }
+
+            let result =
+                self.call_device("connect", adapter, address, DeviceProperty::Connected(true));
+            Box::pin(async { result })
+        }
+
+        fn disconnect<'a>(
+            &'a self,
+            adapter: &'a str,
+            address: Address,
+        ) -> BoxFuture<'a, bluer::Result<()>> {
+            let result = self.call_device(
+                "disconnect",
+                adapter,
+                address,
+                DeviceProperty::Connected(false),
+            );
+            Box::pin(async { result })
+        }
+
+        fn pair<'a>(
+            &'a self,
+            adapter: &'a str,
+            address: Address,
+        ) -> BoxFuture<'a, bluer::Result<()>> {
+            let result = self.call_device("pair", adapter, address, DeviceProperty::Paired(true));
+            Box::pin(async { result })
+        }
+
+        fn set_trusted<'a>(
+            &'a self,
+            adapter: &'a str,
+            address: Address,
+            trusted: bool,
+        ) -> BoxFuture<'a, bluer::Result<()>> {
+            let result = self.call_device(
+                "set_trusted",
+                adapter,
+                address,
+                DeviceProperty::Trusted(trusted),
+            );
+            Box::pin(async { result })
+        }
+
+        fn set_blocked<'a>(
+            &'a self,
+            adapter: &'a str,
+            address: Address,
+            blocked: bool,
+        ) -> BoxFuture<'a, bluer::Result<()>> {
+            let result = self.call_device(
+                "set_blocked",
+                adapter,
+                address,
+                DeviceProperty::Blocked(blocked),
+            );
+            Box::pin(async { result })
+        }
+
+        fn set_alias<'a>(
+            &'a self,
+            adapter: &'a str,
+            address: Address,
+            alias: String,
+        ) -> BoxFuture<'a, bluer::Result<()>> {
+            let result =
+                self.call_device("set_alias", adapter, address, DeviceProperty::Alias(alias));
+            Box::pin(async { result })
+        }
+
+        fn rfkill(&self) -> BoxFuture<'_, io::Result<Rfkill>> {
+            let rfkill = self.state.lock().unwrap().rfkill.clone();
+            Box::pin(async { Ok(rfkill) })
+        }
+
+        fn watch_rfkill(&self, tx: Sender<Rfkill>) -> io::Result<()> {
+            self.state.lock().unwrap().rfkill_tx = Some(tx);
+            Ok(())
+        }
+
+        fn set_soft_blocked<'a>(
+            &'a self,
+            adapter: &'a str,
+            blocked: bool,
+        ) -> BoxFuture<'a, io::Result<()>> {
+            self.soft_block(adapter, blocked);
+            Box::pin(async { Ok(()) })
+        }
+
+        fn set_all_soft_blocked(&self, blocked: bool) -> BoxFuture<'_, io::Result<()>> {
+            let adapters = {
+                let mut state = self.state.lock().unwrap();
+                state
+                    .calls
+                    .push(format!("set_all_soft_blocked {}", blocked));
+                state.adapters.keys().cloned().collect::<Vec<_>>()
+            };
+
+            for adapter in adapters {
+                self.soft_block(&adapter, blocked);
+            }
+
+            Box::pin(async { Ok(()) })
+        }
+    }
+}
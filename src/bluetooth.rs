@@ -1,30 +1,232 @@
-use std::{cmp::Ordering, time::Duration};
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use anyhow::Result;
-use bluer::{Adapter, Address, Session};
-use futures::{FutureExt, StreamExt, stream::FuturesUnordered};
-use log::error;
+use bluer::{
+    AdapterEvent, AdapterProperty, Address, DeviceEvent, DeviceProperty, ErrorKind, Session,
+    SessionEvent, agent::AgentHandle,
+};
+use futures::{
+    FutureExt, StreamExt,
+    stream::{self, AbortHandle, BoxStream, FuturesUnordered, SelectAll},
+};
+use log::{debug, error, info};
 use tokio::{
-    process::Command,
-    sync::mpsc::{Sender, channel},
+    sync::{
+        Notify,
+        mpsc::{Sender, channel},
+        oneshot,
+    },
+    task::JoinHandle,
+    time::Instant,
+};
+use zbus::proxy;
+
+use crate::{
+    agent::register_agent,
+    app::AppEvent,
+    audio::{self, AudioBackend, AudioCard, Pactl},
+    backend::{Bluer, BluetoothBackend},
+    config,
+    favourites::Favourites,
+    kind::DeviceKind,
+    rfkill::Rfkill,
 };
 
-use crate::app::AppEvent;
+/// Upper bound on the wait between two attempts to reconnect a favourite device.
+const RECONNECT_MAX_DELAY_MS: u64 = 60_000;
+
+/// Upper bound on how long a stale available device stays in the menu past its window.
+const STALE_CHECK_MAX_SECS: u64 = 10;
 
-const STATE_CHANGED_FAILED_RETRY_MS: u64 = 5_000;
+/// The sound server takes a moment to set up the card of a device that just connected.
+const AUDIO_CARD_DELAY_MS: u64 = 2_000;
 
 #[derive(Debug)]
 pub enum Action {
     ToggleBluetooth,
     ToggleDevice(BTDevice),
+    PairDevice(BTDevice),
+    ToggleTrust(BTDevice),
+    ForgetDevice(Address),
+    ToggleFavourite(BTDevice),
+    StartScan,
+    StopScan,
+    SelectAdapter(String),
+    SetAudioProfile(Address, String),
+    BlockDevice(Address),
+    UnblockDevice(Address),
+    /// Sets the device's alias. An empty name goes back to the one the device advertises.
+    RenameDevice(Address, String),
+    /// Soft blocks every radio, not just bluetooth, or unblocks them again.
+    ToggleAirplaneMode,
+    /// Lets other devices find the adapter, until the configured timeout runs out.
+    ToggleDiscoverable,
+    /// Lets other devices pair with the adapter, until the configured timeout runs out.
+    TogglePairable,
+}
+
+impl Action {
+    /// The device an action works on, for actions that take long enough to show progress for.
+    pub fn operation(&self) -> Option<(Address, DeviceOperation)> {
+        match self {
+            Action::ToggleDevice(device) if device.is_on() => {
+                Some((device.address, DeviceOperation::Disconnecting))
+            }
+            Action::ToggleDevice(device) => Some((device.address, DeviceOperation::Connecting)),
+            Action::PairDevice(device) => Some((device.address, DeviceOperation::Pairing)),
+            _ => None,
+        }
+    }
+}
+
+/// An action on a device that hasn't finished yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceOperation {
+    Connecting,
+    Disconnecting,
+    Pairing,
+}
+
+impl DeviceOperation {
+    pub fn label(&self) -> &'static str {
+        match self {
+            DeviceOperation::Connecting => "Connecting…",
+            DeviceOperation::Disconnecting => "Disconnecting…",
+            DeviceOperation::Pairing => "Pairing…",
+        }
+    }
+
+    pub fn verb(&self) -> &'static str {
+        match self {
+            DeviceOperation::Connecting => "connect",
+            DeviceOperation::Disconnecting => "disconnect",
+            DeviceOperation::Pairing => "pair",
+        }
+    }
+}
+
+/// Adapter modes that BlueZ turns off by itself after a timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum AdapterMode {
+    Discoverable,
+    Pairable,
+}
+
+impl AdapterMode {
+    fn label(self) -> &'static str {
+        match self {
+            AdapterMode::Discoverable => "discoverable",
+            AdapterMode::Pairable => "pairable",
+        }
+    }
+
+    async fn timeout(self, backend: &dyn BluetoothBackend, adapter: &str) -> bluer::Result<u32> {
+        match self {
+            AdapterMode::Discoverable => backend.discoverable_timeout(adapter).await,
+            AdapterMode::Pairable => backend.pairable_timeout(adapter).await,
+        }
+    }
 }
 
+/// Where the result of a request goes when someone is waiting for it, e.g. a D-Bus caller.
+pub type Reply = oneshot::Sender<Result<(), BTFailure>>;
+
 #[derive(Debug)]
 pub enum BTEvent {
     Init(BTState),
-    Request { action: Action, state: BTState },
+    Request {
+        action: Action,
+        state: BTState,
+        reply: Option<Reply>,
+    },
+}
+
+/// Why a bluetooth operation failed, in terms that tell the user what to do about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BTError {
+    NotReady,
+    AuthFailed,
+    PageTimeout,
+    InProgress,
+    RfkillBlocked,
+    NotFound,
+    TimedOut,
+    Other(String),
 }
 
+impl From<bluer::Error> for BTError {
+    fn from(e: bluer::Error) -> Self {
+        let message = e.message.to_lowercase();
+
+        // BlueZ reports some of these as a generic failure with a telling message.
+        if message.contains("rfkill") {
+            return BTError::RfkillBlocked;
+        }
+
+        if message.contains("page-timeout") || message.contains("page timeout") {
+            return BTError::PageTimeout;
+        }
+
+        match e.kind {
+            ErrorKind::NotReady => BTError::NotReady,
+            ErrorKind::AuthenticationCanceled
+            | ErrorKind::AuthenticationFailed
+            | ErrorKind::AuthenticationRejected
+            | ErrorKind::AuthenticationTimeout => BTError::AuthFailed,
+            ErrorKind::InProgress | ErrorKind::AlreadyExists => BTError::InProgress,
+            ErrorKind::DoesNotExist | ErrorKind::NotFound => BTError::NotFound,
+            _ if e.message.is_empty() => BTError::Other(e.kind.to_string()),
+            _ => BTError::Other(e.message),
+        }
+    }
+}
+
+impl fmt::Display for BTError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BTError::NotReady => write!(f, "bluetooth is off or not ready"),
+            BTError::AuthFailed => write!(f, "pairing was rejected or timed out"),
+            BTError::PageTimeout => write!(f, "the device didn't respond, is it on and nearby?"),
+            BTError::InProgress => write!(f, "the device is busy with another request"),
+            BTError::RfkillBlocked => write!(f, "bluetooth is blocked by rfkill"),
+            BTError::NotFound => write!(f, "the device is gone"),
+            BTError::TimedOut => write!(f, "it took too long"),
+            BTError::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// A failed `Action`, reported back to the UI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BTFailure {
+    /// What was being done, e.g. "connect WH-1000XM4".
+    pub operation: String,
+    pub error: BTError,
+}
+
+impl BTFailure {
+    fn new(operation: impl Into<String>, e: bluer::Error) -> Self {
+        Self {
+            operation: operation.into(),
+            error: BTError::from(e),
+        }
+    }
+}
+
+impl fmt::Display for BTFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Couldn't {}: {}", self.operation, self.error)
+    }
+}
+
+impl std::error::Error for BTFailure {}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum BTDeviceStatus {
     Paired,
@@ -32,13 +234,36 @@ pub enum BTDeviceStatus {
     Disconnected,
 }
 
+/// How the last attempt to automatically reconnect a favourite device went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reconnect {
+    Trying { attempt: u32 },
+    Connected,
+    Failed,
+}
+
 #[derive(Debug, Clone)]
 pub struct BTDevice {
     pub name: String,
+    /// The name the device gives itself. `name` follows it until the device is renamed.
+    pub advertised_name: Option<String>,
     pub address: Address,
     pub status: BTDeviceStatus,
     pub battery_percentage: Option<u8>,
+    /// Only reported while the adapter is discovering, or for a while after.
+    pub rssi: Option<i16>,
+    pub tx_power: Option<i16>,
+    /// When `rssi` was last reported.
+    pub last_seen: Option<Instant>,
     pub is_paired: bool,
+    pub is_trusted: bool,
+    /// BlueZ rejects every connection from or to a blocked device.
+    pub is_blocked: bool,
+    pub kind: DeviceKind,
+    pub is_favourite: bool,
+    pub reconnect: Option<Reconnect>,
+    /// Only set while an audio device is connected.
+    pub audio: Option<AudioCard>,
 }
 
 impl BTDevice {
@@ -47,35 +272,142 @@ impl BTDevice {
     }
 
     pub async fn from_device(device: &bluer::Device) -> Self {
-        let (mut name, is_paired, is_connected, battery_percentage) = futures::join!(
-            device.name().map(|res| res
-                .ok()
-                .flatten()
-                .unwrap_or_else(|| device.address().to_string())),
+        // The alias is what the user named the device. BlueZ sets it to the advertised name until
+        // then.
+        let (alias, name, is_paired, is_trusted, is_connected, battery_percentage) = futures::join!(
+            device.alias().map(Result::ok),
+            device.name().map(|res| res.ok().flatten()),
             device.is_paired().map(Result::unwrap_or_default),
+            device.is_trusted().map(Result::unwrap_or_default),
             device.is_connected().map(Result::unwrap_or_default),
             device.battery_percentage().map(|res| res.ok().flatten()),
         );
 
-        if name.is_empty() {
-            name = device.address().to_string();
-        };
+        let (is_blocked, icon, class, appearance, uuids, rssi, tx_power) = futures::join!(
+            device.is_blocked().map(Result::unwrap_or_default),
+            device.icon().map(|res| res.ok().flatten()),
+            device.class().map(|res| res.ok().flatten()),
+            device.appearance().map(|res| res.ok().flatten()),
+            device.uuids().map(|res| res.ok().flatten()),
+            device.rssi().map(|res| res.ok().flatten()),
+            device.tx_power().map(|res| res.ok().flatten()),
+        );
 
-        let status = if is_connected {
-            BTDeviceStatus::Connected
-        } else if is_paired {
-            BTDeviceStatus::Paired
-        } else {
-            BTDeviceStatus::Disconnected
-        };
+        let advertised_name = name;
+        let name = [alias, advertised_name.clone()]
+            .into_iter()
+            .flatten()
+            .find(|name| !name.is_empty())
+            .unwrap_or_else(|| device.address().to_string());
 
         Self {
             name,
+            advertised_name,
             address: device.address(),
-            status,
+            status: Self::status(is_paired, is_connected),
             battery_percentage,
+            rssi,
+            tx_power,
+            last_seen: rssi.map(|_| Instant::now()),
             is_paired,
+            is_trusted,
+            is_blocked,
+            kind: DeviceKind::detect(icon.as_deref(), class, appearance, uuids.as_ref()),
+            is_favourite: false,
+            reconnect: None,
+            audio: None,
+        }
+    }
+
+    fn status(is_paired: bool, is_connected: bool) -> BTDeviceStatus {
+        if is_connected {
+            BTDeviceStatus::Connected
+        } else if is_paired {
+            BTDeviceStatus::Paired
+        } else {
+            BTDeviceStatus::Disconnected
+        }
+    }
+
+    /// Signal strength as bars for the menu, when the device has been heard from recently.
+    pub fn signal_bars(&self, stale_after: Duration) -> Option<&'static str> {
+        if !self.is_fresh(stale_after) {
+            return None;
+        }
+
+        Some(match self.rssi? {
+            -55.. => "▂▄▆█",
+            -67.. => "▂▄▆",
+            -80.. => "▂▄",
+            _ => "▂",
+        })
+    }
+
+    fn is_fresh(&self, stale_after: Duration) -> bool {
+        self.last_seen
+            .is_some_and(|last_seen| last_seen.elapsed() < stale_after)
+    }
+
+    fn update_kind(&mut self, kind: DeviceKind) {
+        if kind != DeviceKind::Other {
+            self.kind = kind;
+        }
+    }
+
+    /// Applies a property change reported by BlueZ. Returns `false` when the property isn't one
+    /// we keep track of.
+    pub fn apply(&mut self, property: DeviceProperty) -> bool {
+        match property {
+            // BlueZ changes the alias along with the name, unless the device was renamed. The name
+            // is followed as well, in case the alias change goes missing.
+            DeviceProperty::Name(name) => {
+                let previous = self
+                    .advertised_name
+                    .clone()
+                    .unwrap_or_else(|| self.address.to_string());
+
+                if self.name == previous {
+                    self.name = name.clone();
+                }
+
+                self.advertised_name = Some(name);
+            }
+            DeviceProperty::Alias(name) => {
+                self.name = if name.is_empty() {
+                    self.address.to_string()
+                } else {
+                    name
+                };
+            }
+            DeviceProperty::Paired(is_paired) => {
+                self.is_paired = is_paired;
+                self.status = Self::status(is_paired, self.is_on());
+            }
+            DeviceProperty::Connected(is_connected) => {
+                self.status = Self::status(self.is_paired, is_connected);
+            }
+            DeviceProperty::Trusted(is_trusted) => self.is_trusted = is_trusted,
+            DeviceProperty::Blocked(is_blocked) => self.is_blocked = is_blocked,
+            DeviceProperty::BatteryPercentage(percentage) => {
+                self.battery_percentage = Some(percentage)
+            }
+            // These usually only show up once, while the device is being discovered. Any one of
+            // them that tells us something is good enough.
+            DeviceProperty::Icon(icon) => self.update_kind(DeviceKind::from_icon(&icon)),
+            DeviceProperty::Class(class) => self.update_kind(DeviceKind::from_class(class)),
+            DeviceProperty::Appearance(appearance) => {
+                self.update_kind(DeviceKind::from_appearance(appearance))
+            }
+            DeviceProperty::Uuids(uuids) => self.update_kind(DeviceKind::from_uuids(&uuids)),
+            DeviceProperty::Rssi(rssi) => {
+                self.rssi = Some(rssi);
+                self.last_seen = Some(Instant::now());
+            }
+            DeviceProperty::TxPower(tx_power) => self.tx_power = Some(tx_power),
+            _ => return false,
         }
+
+        true
     }
 }
 
@@ -103,258 +435,2520 @@ impl PartialEq for BTDevice {
 }
 
 #[derive(Debug, Clone, Default)]
-pub struct BTState {
+pub struct BTAdapter {
+    pub name: String,
+    pub alias: String,
     pub on: bool,
+    /// Blocked through rfkill, e.g. by `rfkill block bluetooth`.
+    pub soft_blocked: bool,
+    /// Blocked by a hardware switch or the firmware, which software can't undo.
+    pub hard_blocked: bool,
+    pub scanning: bool,
+    /// Whether we're scanning on the adapter ourselves, which is the only scan we can stop.
+    pub scan_started_here: bool,
+    pub discoverable: bool,
+    pub pairable: bool,
+    /// When BlueZ turns discoverable mode off again. Only known when it was turned on while the
+    /// applet was running, and never set when it stays on.
+    pub discoverable_until: Option<Instant>,
+    pub pairable_until: Option<Instant>,
     pub paired_devices: Vec<BTDevice>,
     pub available_devices: Vec<BTDevice>,
+    /// Blocked devices that aren't paired, left out of `available_devices`. Paired ones stay in
+    /// `paired_devices`.
+    pub blocked_devices: Vec<BTDevice>,
 }
 
-async fn toggle_bluetooth(adapter: &Adapter, on: bool) {
-    //FROM: https://github.com/pop-os/cosmic-applets/blob/c539b0628be7ea66feb3840fdca60c9e59bf3c75/cosmic-applet-bluetooth/src/bluetooth.rs#L678-L710
-    if let Err(e) = adapter.set_powered(!on).await {
-        error!(
-            "Failed to power {} bluetooth adapter. {e:?}",
-            if on { "off" } else { "on" },
-        );
+impl BTAdapter {
+    /// For failure messages. Falls back to the address of devices that aren't listed.
+    pub fn device_name(&self, address: Address) -> String {
+        self.paired_devices
+            .iter()
+            .chain(&self.available_devices)
+            .chain(&self.blocked_devices)
+            .find(|device| device.address == address)
+            .map_or_else(|| address.to_string(), |device| device.name.clone())
     }
+}
 
-    // rfkill will be persisted after reboot
-    let name = adapter.name();
-    let device_id = Command::new("rfkill")
-        .arg("list")
-        .arg("-n")
-        .arg("--output")
-        .arg("ID,DEVICE")
-        .output()
-        .await
-        .ok()
-        .and_then(|o| {
-            // Output looks like this:
-            // 0 acer-wireless
-            // 1 acer-bluetooth
-            // 2 hci0
-            // 3 phy0
-            //
-            // The adapter names are the same as the device names on the second column.
-            // So we need to find the name of the deafault adapter on the second column and
-            // return the ID of the adapter.
-            let lines = String::from_utf8(o.stdout).ok()?;
-            lines.split("\n").find_map(|row| {
-                let (id, cname) = row.trim().split_once(" ")?;
-                (name == cname).then_some(id.to_string())
-            })
-        });
+#[derive(Debug, Clone, Default)]
+pub struct BTState {
+    pub adapters: Vec<BTAdapter>,
+    pub selected_adapter: Option<String>,
+    /// Every radio on the system is soft blocked.
+    pub airplane_mode: bool,
+    /// Filled in by `App` from the requests it's waiting on.
+    pub operations: HashMap<Address, DeviceOperation>,
+}
 
-    if let Some(id) = device_id {
-        if let Err(e) = Command::new("rfkill")
-            .arg(if on { "block" } else { "unblock" })
-            .arg(id)
-            .output()
-            .await
-        {
-            error!("Failed to set bluetooth state using rfkill. {e:?}");
-        };
+impl BTState {
+    /// The adapter the tray and incoming actions are working with.
+    pub fn adapter(&self) -> Option<&BTAdapter> {
+        self.adapters
+            .iter()
+            .find(|adapter| Some(&adapter.name) == self.selected_adapter.as_ref())
     }
+}
 
-    tokio::time::sleep(Duration::from_millis(100)).await;
+/// The devices known on a single adapter, kept up to date from BlueZ's events so that building a
+/// `BTState` doesn't have to query every device again.
+#[derive(Debug, Clone, Default)]
+struct DeviceMap {
+    devices: HashMap<Address, BTDevice>,
 }
 
-async fn toggle_device(adapter: &Adapter, address: &Address, on: bool) {
-    let device = match adapter.device(*address) {
-        Ok(device) => device,
-        Err(e) => {
-            error!("Failed to get bluetooth device. {e:?}");
-            return;
-        }
-    };
-    let res = if on {
-        device.disconnect().await
-    } else {
-        device.connect().await
-    };
+impl DeviceMap {
+    fn contains(&self, address: &Address) -> bool {
+        self.devices.contains_key(address)
+    }
 
-    if let Err(e) = res {
-        error!("Failed to set bluetooth device state. {e:?}");
+    fn insert(&mut self, device: BTDevice) {
+        self.devices.insert(device.address, device);
     }
-}
 
-async fn listen_for_unexpected_adapter_power_changes(app_tx: Sender<AppEvent>, adapter: Adapter) {
-    let mut on = adapter.is_powered().await.unwrap_or_default();
-    let mut interval = tokio::time::interval(Duration::from_secs(10));
+    fn remove(&mut self, address: &Address) -> bool {
+        self.devices.remove(address).is_some()
+    }
 
-    loop {
-        interval.tick().await;
+    fn apply(&mut self, address: &Address, property: DeviceProperty) -> bool {
+        self.devices
+            .get_mut(address)
+            .is_some_and(|device| device.apply(property))
+    }
+
+    /// Notes that BlueZ has just announced the device, e.g. because a scan found it. Not every
+    /// device found that way reports its signal strength.
+    fn seen(&mut self, address: &Address) {
+        if let Some(device) = self.devices.get_mut(address) {
+            device.last_seen = Some(Instant::now());
+        }
+    }
 
-        let new_on = adapter.is_powered().await.unwrap_or_default();
+    /// Splits the devices into paired, available and blocked devices, all sorted. Available
+    /// devices that haven't been heard from within `stale_after`, if given, are left out, strongest
+    /// signal first. Devices BlueZ only knows from its cache count as heard from once a scan announces
+    /// them again or they report their signal strength.
+    fn split(
+        &self,
+        stale_after: Option<Duration>,
+    ) -> (Vec<BTDevice>, Vec<BTDevice>, Vec<BTDevice>) {
+        let (mut paired_devices, devices): (Vec<_>, Vec<_>) = self
+            .devices
+            .values()
+            .cloned()
+            .partition(|device| device.is_paired);
 
-        if on != new_on {
-            on = new_on;
+        let (mut blocked_devices, mut available_devices): (Vec<_>, Vec<_>) =
+            devices.into_iter().partition(|device| device.is_blocked);
 
-            let state = build_state(&adapter).await.unwrap_or_default();
-            let _ = app_tx.send(AppEvent::Response(state)).await;
+        if let Some(stale_after) = stale_after {
+            available_devices.retain(|device| device.is_fresh(stale_after));
         }
+
+        paired_devices.sort();
+        available_devices.sort_by(|a, b| b.rssi.cmp(&a.rssi).then_with(|| a.cmp(b)));
+        blocked_devices.sort();
+
+        (paired_devices, available_devices, blocked_devices)
     }
 }
 
-async fn listen_for_device_changes(app_tx: Sender<AppEvent>, adapter: Adapter) {
-    let mut count = 0;
-    let mut interval = tokio::time::interval(Duration::from_secs(1));
-    let mut stream = loop {
-        if let Ok(stream) = adapter.discover_devices_with_changes().await {
-            break stream;
-        };
-
-        interval.tick().await;
+/// Who a state is built for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StateView {
+    /// Leaves out available devices that went quiet, and adds the sound cards of connected ones.
+    Applet,
+    /// Lists every device BlueZ knows, since the command line client doesn't scan, and skips the
+    /// sound cards it doesn't show.
+    Cli,
+}
 
-        if count > 10 {
-            return;
+impl StateView {
+    fn stale_after(self) -> Option<Duration> {
+        match self {
+            StateView::Applet => Some(Duration::from_millis(
+                config::current().bluetooth.device_stale_ms,
+            )),
+            StateView::Cli => None,
         }
+    }
+}
 
-        count += 1;
-    };
+/// Shared by the request loop and every adapter listener so that each of them can publish a
+/// `BTState` covering all adapters.
+#[derive(Clone)]
+struct BTContext {
+    app_tx: Sender<AppEvent>,
+    backend: Arc<dyn BluetoothBackend>,
+    selected_adapter: Arc<Mutex<Option<String>>>,
+    devices: Arc<Mutex<HashMap<String, DeviceMap>>>,
+    /// Held while an adapter's devices are loaded, so that they're only loaded once when a state
+    /// is built while the device listener starts.
+    loading: Arc<tokio::sync::Mutex<()>>,
+    favourites: Arc<Mutex<Favourites>>,
+    reconnects: Arc<Mutex<HashMap<Address, Reconnect>>>,
+    audio: Arc<dyn AudioBackend>,
+    /// The sound cards last listed. `None` until they're next needed, which is after a device
+    /// connects or disconnects, or a profile is switched.
+    audio_cards: Arc<Mutex<Option<HashMap<Address, AudioCard>>>>,
+    rfkill: Arc<Mutex<Rfkill>>,
+    /// When discoverable or pairable mode runs out, per adapter.
+    deadlines: Arc<Mutex<HashMap<(String, AdapterMode), Instant>>>,
+    /// Scans started with `Action::StartScan`, per adapter.
+    scans: Arc<Mutex<HashMap<String, JoinHandle<()>>>>,
+    publish: Arc<Notify>,
+}
+
+impl BTContext {
+    async fn new(
+        app_tx: Sender<AppEvent>,
+        backend: Arc<dyn BluetoothBackend>,
+        favourites: Favourites,
+        audio: Arc<dyn AudioBackend>,
+    ) -> Self {
+        let default_adapter = backend.default_adapter().await.ok();
+
+        // Not every system has rfkill, in which case nothing is ever blocked.
+        let rfkill = backend.rfkill().await.unwrap_or_else(|e| {
+            debug!("Failed to read rfkill state. {e:?}");
+            Rfkill::default()
+        });
 
-    while (stream.next().await).is_some() {
-        if let Ok(state) = build_state(&adapter).await {
-            let _ = app_tx.send(AppEvent::Response(state)).await;
+        Self {
+            app_tx,
+            backend,
+            selected_adapter: Arc::new(Mutex::new(default_adapter)),
+            devices: Arc::new(Mutex::new(HashMap::new())),
+            loading: Arc::new(tokio::sync::Mutex::new(())),
+            favourites: Arc::new(Mutex::new(favourites)),
+            reconnects: Arc::new(Mutex::new(HashMap::new())),
+            audio,
+            audio_cards: Arc::new(Mutex::new(None)),
+            rfkill: Arc::new(Mutex::new(rfkill)),
+            deadlines: Arc::new(Mutex::new(HashMap::new())),
+            scans: Arc::new(Mutex::new(HashMap::new())),
+            publish: Arc::new(Notify::new()),
         }
     }
-}
 
-pub async fn init_bluetooth(app_tx: Sender<AppEvent>) -> Result<Sender<BTEvent>> {
-    let (tx, mut rx) = channel::<BTEvent>(32);
+    fn select_adapter(&self, name: Option<String>) {
+        *self.selected_adapter.lock().unwrap() = name;
+    }
 
-    // FROM: https://github.com/pop-os/cosmic-applets/blob/c171f048a6dff1a032eb5edf8f343cac60971ac5/cosmic-applet-bluetooth/src/bluetooth.rs#L82,L97
-    //
-    // ChatGPT says this code is attempting to establish a session with retry logic, using exponential backoff.
-    // - 2_u64 is just the literal integer (i32) 2, but instucts the compiler treat it as a u64.
-    //   saturating_pow is implemented for u64 and not i32. the rust compiler would assume the
-    //   integer was i32 if it was not explicitly specified.
-    // - u32 is the same as u64 but is a smaller integer type.
-    // - each iteration of the loop adds 1 to the retry_count, meaning the result of 2^retry_count
-    //   increases exponentially in each iteration.
-    // - 2^retry_count (exponential growth)
-    // - they use .saturating_pow(u32) instead of .pow(u32) to avoid overflowing. it returns the
-    //   max or min bound when the result is too large to fit in the return type.
-    // - 68_719_476_734 is the time in milliseconds. This is actially 2.18 years.
-    // - the original code chained `.max(68719476734)` on to the result of the pow call, but
-    //   ChatGPT (which I also agree after looking at the docs) says that it's possibly a
-    //   mistake/bug. `.max(68719476734)` compares the result of the pow call to 68719476734,
-    //   and returns the larger of the two. Meaning, on the first iteration, the result of the
-    //   pow call will be 2, which is less than 68719476734, so 68719476734 will be returned.
-    //   Therefore, the loop will wait for 2 years before retrying!
-    //   I have created a [PR](https://github.com/pop-os/cosmic-applets/issues/997) to fix this.
-    // - ChatGPT suggest that `.min(68719476734)` should be used instead of `.max(68719476734)`.
-    //   This will enable the exponential backoff to work correctly as it will exponentially
-    //   increase from 2 to 68719476734.
+    fn update_devices<T>(&self, adapter: &str, f: impl FnOnce(&mut DeviceMap) -> T) -> T {
+        let mut devices = self.devices.lock().unwrap();
+        f(devices.entry(adapter.to_string()).or_default())
+    }
 
-    let mut retry_count = 0u32;
+    fn forget_adapter(&self, name: &str) {
+        self.devices.lock().unwrap().remove(name);
+        self.stop_scan(name);
+    }
 
-    // Initialize connection.
-    let session = loop {
-        if let Ok(session) = Session::new().await {
-            break session;
+    fn is_scanning(&self, adapter: &str) -> bool {
+        self.scans
+            .lock()
+            .unwrap()
+            .get(adapter)
+            .is_some_and(|task| !task.is_finished())
+    }
+
+    fn start_scan(&self, adapter: &str) {
+        if !self.is_scanning(adapter) {
+            let task = tokio::spawn(scan(self.clone(), adapter.to_string()));
+            self.scans.lock().unwrap().insert(adapter.to_string(), task);
         }
+    }
 
-        // will run up to retry_count = 16 which 65,536 milliseconds which is roughly 1.1 seconds.
-        if retry_count >= 16 {
-            anyhow::bail!("Failed to connect to Bluetooth session");
+    /// Returns whether there was a scan of ours to stop.
+    fn stop_scan(&self, adapter: &str) -> bool {
+        match self.scans.lock().unwrap().remove(adapter) {
+            Some(task) => {
+                let running = !task.is_finished();
+                task.abort();
+                running
+            }
+            None => false,
         }
+    }
 
-        retry_count = retry_count.saturating_add(1);
-        _ = tokio::time::sleep(Duration::from_millis(
-            2_u64.saturating_pow(retry_count).min(65_536),
-        ))
-        .await;
-    };
+    /// Returns the cached devices of the adapter, loading all of them from BlueZ if the adapter
+    /// hasn't been seen before.
+    async fn devices(
+        &self,
+        adapter: &str,
+        stale_after: Option<Duration>,
+    ) -> (Vec<BTDevice>, Vec<BTDevice>, Vec<BTDevice>) {
+        if let Some(devices) = self.devices.lock().unwrap().get(adapter) {
+            return devices.split(stale_after);
+        }
 
-    let adapter = session.default_adapter().await?;
-    let state = build_state(&adapter).await?;
+        let _loading = self.loading.lock().await;
 
-    tx.send(BTEvent::Init(state)).await?;
+        // Loaded by someone else while we waited.
+        if let Some(devices) = self.devices.lock().unwrap().get(adapter) {
+            return devices.split(stale_after);
+        }
 
-    tokio::spawn(listen_for_device_changes(app_tx.clone(), adapter.clone()));
-    tokio::spawn(listen_for_unexpected_adapter_power_changes(
-        app_tx.clone(),
-        adapter.clone(),
-    ));
+        let devices = load_devices(&*self.backend, adapter).await;
+        let split = devices.split(stale_after);
 
-    tokio::spawn(async move {
-        while let Some(action) = rx.recv().await {
-            match action {
-                BTEvent::Init(btstate) => {
-                    if let Err(e) = app_tx.send(AppEvent::Response(btstate)).await {
-                        error!("Failed to send BTState to AppEvent::Response: {e}");
-                    };
-                }
-                BTEvent::Request { action, state } => {
-                    match action {
-                        Action::ToggleBluetooth => {
-                            toggle_bluetooth(&adapter, state.on).await;
+        self.devices
+            .lock()
+            .unwrap()
+            .entry(adapter.to_string())
+            .or_insert(devices);
 
-                            // There's a significant delay when turning off the adapter. Borrowing some ideas from GNOME's
-                            // bluetooth applet.
-                            // FROM: https://github.com/GNOME/gnome-shell/blob/4272916830120c0ff858e9b9de5d242a04932632/js/ui/status/bluetooth.js#L123-L140
-                            let app_tx = app_tx.clone();
-                            let local_adapter = adapter.clone();
-                            tokio::spawn(async move {
-                                tokio::time::sleep(Duration::from_millis(
-                                    STATE_CHANGED_FAILED_RETRY_MS,
-                                ))
-                                .await;
+        split
+    }
 
-                                if let Ok(state) = build_state(&local_adapter).await {
-                                    let _ = app_tx.send(AppEvent::Response(state)).await;
-                                }
-                            });
-                        }
-                        Action::ToggleDevice(device) => {
-                            toggle_device(&adapter, &device.address, device.is_on()).await
-                        }
-                    }
+    fn deadline(&self, adapter: &str, mode: AdapterMode) -> Option<Instant> {
+        self.deadlines
+            .lock()
+            .unwrap()
+            .get(&(adapter.to_string(), mode))
+            .copied()
+    }
 
-                    if let Ok(state) = build_state(&adapter).await {
-                        let _ = app_tx.send(AppEvent::Response(state)).await;
-                    }
-                }
-            }
+    async fn set_favourite(&self, address: Address, favourite: bool) {
+        // Saved from a copy, so that the file isn't written while holding the lock.
+        let changed = {
+            let mut favourites = self.favourites.lock().unwrap();
+            favourites
+                .set(address, favourite)
+                .then(|| favourites.clone())
+        };
+
+        if !favourite {
+            self.reconnects.lock().unwrap().remove(&address);
         }
-    });
 
-    Ok(tx)
-}
+        if let Some(favourites) = changed {
+            favourites.save().await;
+        }
+    }
 
-async fn build_state(adapter: &Adapter) -> Result<BTState> {
-    let on = adapter.is_powered().await?;
-    let addresses = adapter.device_addresses().await.unwrap_or_default();
+    /// Whether a reconnect that's under way should carry on. It doesn't once the device stops
+    /// being a favourite, or is connected or disconnected from the menu.
+    fn is_reconnecting(&self, address: &Address) -> bool {
+        self.favourites.lock().unwrap().contains(address)
+            && matches!(
+                self.reconnects.lock().unwrap().get(address),
+                Some(Reconnect::Trying { .. })
+            )
+    }
 
-    let mut devices = Vec::with_capacity(addresses.len());
+    fn set_reconnect(&self, address: Address, reconnect: Reconnect) {
+        self.reconnects.lock().unwrap().insert(address, reconnect);
+        self.publish();
+    }
 
-    let mut device_stream = addresses
-        .into_iter()
-        .filter_map(|address| adapter.device(address).ok())
-        .map(async |device| BTDevice::from_device(&device).await)
-        .collect::<FuturesUnordered<_>>();
+    /// Adds what we know about the device beyond what BlueZ reports.
+    fn annotate(&self, device: &mut BTDevice) {
+        device.is_favourite = self.favourites.lock().unwrap().contains(&device.address);
+        device.reconnect = self
+            .reconnects
+            .lock()
+            .unwrap()
+            .get(&device.address)
+            .copied();
+    }
 
-    while let Some(device) = device_stream.next().await {
-        devices.push(device)
+    /// Makes the next state list the sound cards again.
+    fn forget_audio_cards(&self) {
+        *self.audio_cards.lock().unwrap() = None;
     }
 
-    let mut paired_devices = devices
-        .clone()
-        .into_iter()
-        .filter(|device| device.is_paired)
-        .collect::<Vec<BTDevice>>();
-    let mut available_devices = devices
-        .into_iter()
-        .filter(|device| !device.is_paired)
-        .collect::<Vec<BTDevice>>();
+    /// Looks up the sound cards of connected devices. The sound server isn't asked at all while
+    /// nothing is connected, and only again once the cards could have changed.
+    async fn add_audio_cards(&self, adapters: &mut [BTAdapter]) {
+        if !adapters
+            .iter()
+            .any(|adapter| adapter.paired_devices.iter().any(BTDevice::is_on))
+        {
+            return;
+        }
 
-    paired_devices.sort();
-    available_devices.sort();
+        let cached = self.audio_cards.lock().unwrap().clone();
 
-    Ok(BTState {
-        on,
-        paired_devices,
-        available_devices,
-    })
+        let cards = match cached {
+            Some(cards) => cards,
+            None => {
+                // A sound server that isn't there isn't asked again on every change either.
+                let cards = self.audio.cards().await.unwrap_or_else(|e| {
+                    debug!("Failed to list sound cards. {e:?}");
+                    HashMap::new()
+                });
+
+                *self.audio_cards.lock().unwrap() = Some(cards.clone());
+                cards
+            }
+        };
+
+        for adapter in adapters.iter_mut() {
+            for device in adapter.paired_devices.iter_mut() {
+                if device.is_on() {
+                    device.audio = cards.get(&device.address).cloned();
+                }
+            }
+        }
+    }
+
+    async fn build_state(&self) -> Result<BTState> {
+        self.build_state_for(StateView::Applet).await
+    }
+
+    async fn build_state_for(&self, view: StateView) -> Result<BTState> {
+        let mut names = self.backend.adapter_names().await?;
+        names.sort();
+
+        let mut adapters = Vec::with_capacity(names.len());
+
+        for name in &names {
+            if let Ok(state) = build_adapter_state(self, name, view).await {
+                adapters.push(state);
+            }
+        }
+
+        if view == StateView::Applet {
+            self.add_audio_cards(&mut adapters).await;
+        }
+
+        let selected_adapter = {
+            let mut selected_adapter = self.selected_adapter.lock().unwrap();
+
+            // Fall back to the first adapter when the selected one has been unplugged.
+            if selected_adapter
+                .as_ref()
+                .is_none_or(|selected| !names.contains(selected))
+            {
+                *selected_adapter = names.first().cloned();
+            }
+
+            selected_adapter.clone()
+        };
+
+        Ok(BTState {
+            adapters,
+            selected_adapter,
+            airplane_mode: self.rfkill.lock().unwrap().all_soft_blocked(),
+            ..Default::default()
+        })
+    }
+
+    /// Schedules a new `BTState` to be published by `publish_state`.
+    fn publish(&self) {
+        self.publish.notify_one();
+    }
+}
+
+/// How long a state stays accurate without any events, if it doesn't forever. Available devices
+/// drop off once they go quiet. Countdowns are ticked by the tray instead.
+fn refresh_after(state: &BTState) -> Option<Duration> {
+    state
+        .adapters
+        .iter()
+        .any(|adapter| !adapter.available_devices.is_empty())
+        .then(|| {
+            let stale_after = Duration::from_millis(config::current().bluetooth.device_stale_ms);
+            stale_after.min(Duration::from_secs(STALE_CHECK_MAX_SECS))
+        })
+}
+
+async fn publish_state(context: BTContext) {
+    let mut refresh = None;
+
+    loop {
+        match refresh {
+            Some(refresh) => {
+                let _ = tokio::time::timeout(refresh, context.publish.notified()).await;
+            }
+            None => context.publish.notified().await,
+        }
+
+        if let Ok(state) = context.build_state().await {
+            refresh = refresh_after(&state);
+
+            let _ = context.app_tx.send(AppEvent::Response(state)).await;
+        }
+
+        // Anything that changes while we wait here ends up in the next state.
+        let publish_interval_ms = config::current().bluetooth.publish_interval_ms;
+        tokio::time::sleep(Duration::from_millis(publish_interval_ms)).await;
+    }
+}
+
+async fn toggle_bluetooth(
+    backend: &dyn BluetoothBackend,
+    state: &BTAdapter,
+) -> Result<(), BTFailure> {
+    let on = state.on;
+    let operation = format!("turn bluetooth {}", if on { "off" } else { "on" });
+
+    // Nothing in software can undo a hard block, and BlueZ doesn't say why powering on failed.
+    if state.hard_blocked {
+        return Err(BTFailure {
+            operation,
+            error: BTError::RfkillBlocked,
+        });
+    }
+
+    //FROM: https://github.com/pop-os/cosmic-applets/blob/c539b0628be7ea66feb3840fdca60c9e59bf3c75/cosmic-applet-bluetooth/src/bluetooth.rs#L678-L710
+    let powered = backend.set_powered(&state.name, !on).await;
+
+    // rfkill will be persisted after reboot
+    let rfkill_set = match backend.set_soft_blocked(&state.name, on).await {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Failed to set bluetooth state using rfkill. {e:?}");
+            false
+        }
+    };
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    match powered {
+        // Powering on a soft blocked adapter fails, but unblocking it with rfkill takes care of
+        // that.
+        Err(e) if rfkill_set && !on && BTError::from(e.clone()) == BTError::RfkillBlocked => Ok(()),
+        Err(e) => Err(BTFailure::new(operation, e)),
+        Ok(()) => Ok(()),
+    }
+}
+
+async fn toggle_mode(
+    backend: &dyn BluetoothBackend,
+    state: &BTAdapter,
+    mode: AdapterMode,
+) -> Result<(), BTFailure> {
+    let config = config::current();
+    let config = &config.bluetooth;
+
+    let (on, timeout_ms) = match mode {
+        AdapterMode::Discoverable => (!state.discoverable, config.discoverable_timeout_ms),
+        AdapterMode::Pairable => (!state.pairable, config.pairable_timeout_ms),
+    };
+    let timeout_secs = u32::try_from(timeout_ms.div_ceil(1000)).unwrap_or(u32::MAX);
+
+    let result = match mode {
+        AdapterMode::Discoverable => {
+            backend
+                .set_discoverable(&state.name, on, timeout_secs)
+                .await
+        }
+        AdapterMode::Pairable => backend.set_pairable(&state.name, on, timeout_secs).await,
+    };
+
+    result.map_err(|e| {
+        let operation = format!(
+            "turn {} mode {}",
+            mode.label(),
+            if on { "on" } else { "off" }
+        );
+        BTFailure::new(operation, e)
+    })
+}
+
+/// Starts or stops the countdown for the mode. BlueZ doesn't say when a mode was turned on, so
+/// the countdown starts when we hear about it.
+async fn track_mode(context: &BTContext, adapter: &str, mode: AdapterMode, on: bool) {
+    let timeout = if on {
+        mode.timeout(&*context.backend, adapter)
+            .await
+            .unwrap_or_default()
+    } else {
+        0
+    };
+
+    let key = (adapter.to_string(), mode);
+
+    if timeout > 0 {
+        let deadline = Instant::now() + Duration::from_secs(timeout.into());
+        context.deadlines.lock().unwrap().insert(key, deadline);
+    } else {
+        context.deadlines.lock().unwrap().remove(&key);
+    }
+
+    context.publish();
+}
+
+async fn toggle_device(
+    backend: &dyn BluetoothBackend,
+    adapter: &str,
+    device: &BTDevice,
+) -> Result<(), BTFailure> {
+    let (operation, res) = if device.is_on() {
+        (
+            "disconnect",
+            backend.disconnect(adapter, device.address).await,
+        )
+    } else {
+        ("connect", backend.connect(adapter, device.address).await)
+    };
+
+    res.map_err(|e| BTFailure::new(format!("{} {}", operation, device.name), e))
+}
+
+async fn trust_device(
+    backend: &dyn BluetoothBackend,
+    adapter: &str,
+    device: &BTDevice,
+) -> Result<(), BTFailure> {
+    let operation = if device.is_trusted {
+        "untrust"
+    } else {
+        "trust"
+    };
+
+    backend
+        .set_trusted(adapter, device.address, !device.is_trusted)
+        .await
+        .map_err(|e| BTFailure::new(format!("{} {}", operation, device.name), e))
+}
+
+async fn rename_device(
+    backend: &dyn BluetoothBackend,
+    adapter: &BTAdapter,
+    address: Address,
+    name: String,
+) -> Result<(), BTFailure> {
+    backend
+        .set_alias(&adapter.name, address, name)
+        .await
+        .map_err(|e| BTFailure::new(format!("rename {}", adapter.device_name(address)), e))
+}
+
+async fn block_device(
+    backend: &dyn BluetoothBackend,
+    adapter: &BTAdapter,
+    address: Address,
+    blocked: bool,
+) -> Result<(), BTFailure> {
+    let operation = if blocked { "block" } else { "unblock" };
+
+    backend
+        .set_blocked(&adapter.name, address, blocked)
+        .await
+        .map_err(|e| {
+            let operation = format!("{} {}", operation, adapter.device_name(address));
+            BTFailure::new(operation, e)
+        })
+}
+
+async fn forget_device(
+    backend: &dyn BluetoothBackend,
+    adapter: &BTAdapter,
+    address: &Address,
+) -> Result<(), BTFailure> {
+    backend
+        .remove_device(&adapter.name, *address)
+        .await
+        .map_err(|e| BTFailure::new(format!("forget {}", adapter.device_name(*address)), e))
+}
+
+/// Gives up on an operation that BlueZ hasn't finished in time. BlueZ itself may carry on, in
+/// which case the result shows up as a property change later.
+async fn with_timeout(
+    operation: &str,
+    device: &BTDevice,
+    future: impl Future<Output = Result<(), BTFailure>>,
+) -> Result<(), BTFailure> {
+    let timeout = Duration::from_millis(config::current().bluetooth.operation_timeout_ms);
+
+    tokio::time::timeout(timeout, future)
+        .await
+        .unwrap_or_else(|_| {
+            Err(BTFailure {
+                operation: format!("{} {}", operation, device.name),
+                error: BTError::TimedOut,
+            })
+        })
+}
+
+async fn pair_device(
+    backend: &dyn BluetoothBackend,
+    adapter: &str,
+    device: &BTDevice,
+) -> Result<(), BTFailure> {
+    // Pairing can wait on the user typing a passkey, so the agent's prompt timeout is added on
+    // top. A device that stalls without asking for anything still lets go of the menu item in the
+    // end, even though BlueZ may carry on pairing.
+    let bluetooth = &config::current().bluetooth;
+    let timeout =
+        Duration::from_millis(bluetooth.operation_timeout_ms + bluetooth.agent_prompt_timeout_ms);
+
+    match tokio::time::timeout(timeout, backend.pair(adapter, device.address)).await {
+        Ok(result) => result.map_err(|e| BTFailure::new(format!("pair {}", device.name), e))?,
+        Err(_) => {
+            return Err(BTFailure {
+                operation: format!("pair {}", device.name),
+                error: BTError::TimedOut,
+            });
+        }
+    }
+
+    // Trusting the device lets it reconnect on its own later without going through an agent.
+    with_timeout("trust", device, async {
+        backend
+            .set_trusted(adapter, device.address, true)
+            .await
+            .map_err(|e| BTFailure::new(format!("trust {}", device.name), e))
+    })
+    .await?;
+
+    with_timeout("connect", device, async {
+        backend
+            .connect(adapter, device.address)
+            .await
+            .map_err(|e| BTFailure::new(format!("connect {}", device.name), e))
+    })
+    .await
+}
+
+fn reconnect_delay(backoff_ms: u64, attempt: u32) -> Duration {
+    let delay_ms = backoff_ms.saturating_mul(2_u64.saturating_pow(attempt.saturating_sub(1)));
+    Duration::from_millis(delay_ms.min(RECONNECT_MAX_DELAY_MS))
+}
+
+async fn reconnect_device(context: BTContext, adapter: String, address: Address) {
+    let config = config::current();
+    let bluetooth = &config.bluetooth;
+    let backend = context.backend.clone();
+
+    for attempt in 1..=bluetooth.reconnect_attempts {
+        if !context.is_reconnecting(&address) {
+            return;
+        }
+
+        // Give up quietly when the adapter goes away or is turned off. Powering it back on starts
+        // over.
+        if !backend.is_powered(&adapter).await.unwrap_or_default() {
+            context.reconnects.lock().unwrap().remove(&address);
+            context.publish();
+            return;
+        }
+
+        match backend.is_connected(&adapter, address).await {
+            Ok(true) => {
+                context.set_reconnect(address, Reconnect::Connected);
+                return;
+            }
+            Ok(false) => (),
+            Err(_) => break,
+        }
+
+        context.set_reconnect(address, Reconnect::Trying { attempt });
+
+        match backend.connect(&adapter, address).await {
+            Ok(()) => {
+                info!("Reconnected favourite bluetooth device {}", address);
+                context.set_reconnect(address, Reconnect::Connected);
+                return;
+            }
+            Err(e) => info!(
+                "Failed to reconnect {} (attempt {}). {e:?}",
+                address, attempt
+            ),
+        }
+
+        if attempt < bluetooth.reconnect_attempts {
+            tokio::time::sleep(reconnect_delay(bluetooth.reconnect_backoff_ms, attempt)).await;
+        }
+    }
+
+    if !context.is_reconnecting(&address) {
+        return;
+    }
+
+    error!(
+        "Giving up on reconnecting favourite bluetooth device {}",
+        address
+    );
+    context.set_reconnect(address, Reconnect::Failed);
+}
+
+/// Connects every favourite device on the adapter that isn't connected already.
+async fn reconnect_favourites(context: BTContext, adapter: String) {
+    if !context
+        .backend
+        .is_powered(&adapter)
+        .await
+        .unwrap_or_default()
+    {
+        return;
+    }
+
+    let (paired_devices, ..) = context.devices(&adapter, None).await;
+    let favourites = context.favourites.lock().unwrap().clone();
+
+    for device in paired_devices {
+        if !favourites.contains(&device.address) || device.is_on() {
+            continue;
+        }
+
+        // Claim the device first so that overlapping triggers, e.g. resume followed by power-on,
+        // don't start a second attempt.
+        {
+            let mut reconnects = context.reconnects.lock().unwrap();
+
+            if matches!(
+                reconnects.get(&device.address),
+                Some(Reconnect::Trying { .. })
+            ) {
+                continue;
+            }
+
+            reconnects.insert(device.address, Reconnect::Trying { attempt: 0 });
+        }
+
+        tokio::spawn(reconnect_device(
+            context.clone(),
+            adapter.clone(),
+            device.address,
+        ));
+    }
+}
+
+async fn reconnect_all_favourites(context: &BTContext) {
+    for name in context.backend.adapter_names().await.unwrap_or_default() {
+        tokio::spawn(reconnect_favourites(context.clone(), name));
+    }
+}
+
+#[proxy(
+    interface = "org.freedesktop.login1.Manager",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1"
+)]
+trait LoginManager {
+    #[zbus(signal)]
+    fn prepare_for_sleep(&self, start: bool) -> zbus::Result<()>;
+}
+
+/// Reconnects favourite devices after the system wakes up from suspend.
+async fn listen_for_resume(context: BTContext) {
+    let stream = match zbus::Connection::system().await {
+        Ok(connection) => match LoginManagerProxy::new(&connection).await {
+            Ok(proxy) => proxy.receive_prepare_for_sleep().await,
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+
+    let mut stream = match stream {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!("Failed to listen for resume from suspend. {e:?}");
+            return;
+        }
+    };
+
+    while let Some(signal) = stream.next().await {
+        if let Ok(args) = signal.args()
+            && !args.start
+        {
+            info!("Resumed from suspend");
+            reconnect_all_favourites(&context).await;
+        }
+    }
+}
+
+async fn toggle_airplane_mode(
+    backend: &dyn BluetoothBackend,
+    airplane_mode: bool,
+) -> Result<(), BTFailure> {
+    backend
+        .set_all_soft_blocked(!airplane_mode)
+        .await
+        .map_err(|e| BTFailure {
+            operation: format!(
+                "turn airplane mode {}",
+                if airplane_mode { "off" } else { "on" }
+            ),
+            error: BTError::Other(e.to_string()),
+        })
+}
+
+/// Keeps the rfkill state of the adapters up to date. BlueZ only reports that a blocked adapter
+/// is powered off, not why.
+async fn listen_for_rfkill_changes(context: BTContext) {
+    let (tx, mut rx) = channel::<Rfkill>(8);
+
+    if let Err(e) = context.backend.watch_rfkill(tx) {
+        debug!("Failed to listen for rfkill changes. {e:?}");
+        return;
+    }
+
+    while let Some(rfkill) = rx.recv().await {
+        *context.rfkill.lock().unwrap() = rfkill;
+        context.publish();
+    }
+}
+
+async fn listen_for_adapter_property_changes(context: BTContext, adapter: String) {
+    let backend = context.backend.clone();
+    let mut on = backend.is_powered(&adapter).await.unwrap_or_default();
+
+    // PropertiesChanged signals can be missed, e.g. while bluetoothd restarts, so keep a slow poll
+    // of the power state around as a fallback.
+    let mut period = Duration::from_millis(config::current().bluetooth.power_poll_ms);
+    let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+
+    let (mut events, mut listening) = match backend.adapter_events(&adapter).await {
+        Ok(events) => (events, true),
+        Err(e) => {
+            error!("Failed to listen for bluetooth adapter property changes. {e:?}");
+            (futures::stream::empty().boxed(), false)
+        }
+    };
+
+    loop {
+        tokio::select! {
+            event = events.next(), if listening => match event {
+                Some(AdapterEvent::PropertyChanged(AdapterProperty::Powered(new_on))) => {
+                    if new_on && !on {
+                        tokio::spawn(reconnect_favourites(context.clone(), adapter.clone()));
+                    }
+
+                    on = new_on;
+                    context.publish();
+                }
+                Some(AdapterEvent::PropertyChanged(AdapterProperty::Discoverable(on))) => {
+                    track_mode(&context, &adapter, AdapterMode::Discoverable, on).await;
+                }
+                Some(AdapterEvent::PropertyChanged(AdapterProperty::Pairable(on))) => {
+                    track_mode(&context, &adapter, AdapterMode::Pairable, on).await;
+                }
+                // BlueZ restarts the countdown of a mode that's on when its timeout changes.
+                Some(AdapterEvent::PropertyChanged(AdapterProperty::DiscoverableTimeout(_))) => {
+                    let on = backend.is_discoverable(&adapter).await.unwrap_or_default();
+                    track_mode(&context, &adapter, AdapterMode::Discoverable, on).await;
+                }
+                Some(AdapterEvent::PropertyChanged(AdapterProperty::PairableTimeout(_))) => {
+                    let on = backend.is_pairable(&adapter).await.unwrap_or_default();
+                    track_mode(&context, &adapter, AdapterMode::Pairable, on).await;
+                }
+                Some(AdapterEvent::PropertyChanged(AdapterProperty::Discovering(_))) => {
+                    context.publish()
+                }
+                Some(_) => (),
+                None => listening = false,
+            },
+            _ = interval.tick() => {
+                let new_on = backend.is_powered(&adapter).await.unwrap_or_default();
+
+                if on != new_on {
+                    if new_on {
+                        tokio::spawn(reconnect_favourites(context.clone(), adapter.clone()));
+                    }
+
+                    on = new_on;
+                    context.publish();
+                }
+
+                let new_period = Duration::from_millis(config::current().bluetooth.power_poll_ms);
+
+                if period != new_period {
+                    period = new_period;
+                    interval = tokio::time::interval_at(Instant::now() + period, period);
+                }
+            }
+        }
+    }
+}
+
+async fn scan(context: BTContext, adapter: String) {
+    // Discovery runs for as long as this stream is alive. It also ends on its own when something
+    // else stops discovery on the adapter.
+    match context.backend.discover_devices(&adapter).await {
+        Ok(mut stream) => {
+            let scan_timeout_ms = config::current().bluetooth.scan_timeout_ms;
+
+            let _ = tokio::time::timeout(Duration::from_millis(scan_timeout_ms), async {
+                while stream.next().await.is_some() {}
+            })
+            .await;
+        }
+        Err(e) => error!("Failed to start scanning for bluetooth devices. {e:?}"),
+    }
+
+    // Nothing else replaces this task while it runs, so the scan being removed is this one.
+    context.scans.lock().unwrap().remove(&adapter);
+    context.publish();
+}
+
+/// The property changes of every device on an adapter, and a way to stop listening to each.
+#[derive(Default)]
+struct DeviceWatches {
+    changes: SelectAll<BoxStream<'static, (Address, DeviceEvent)>>,
+    watched: HashMap<Address, AbortHandle>,
+}
+
+impl DeviceWatches {
+    /// Starts listening to the device's property changes, unless that's already being done.
+    async fn watch(&mut self, context: &BTContext, adapter: &str, address: Address) {
+        if !self.watched.contains_key(&address)
+            && let Ok(events) = context.backend.device_events(adapter, address).await
+        {
+            let (events, handle) = stream::abortable(events);
+            self.watched.insert(address, handle);
+            self.changes
+                .push(events.map(move |event| (address, event)).boxed());
+        }
+    }
+
+    fn unwatch(&mut self, address: &Address) {
+        if let Some(handle) = self.watched.remove(address) {
+            handle.abort();
+        }
+    }
+}
+
+async fn watch_device(
+    context: &BTContext,
+    adapter: &str,
+    watches: &mut DeviceWatches,
+    address: Address,
+) {
+    // BlueZ announces devices again whenever one of their interfaces is added, e.g. Battery1 on
+    // connecting, and when a scan finds them again.
+    watches.watch(context, adapter, address).await;
+
+    // Only devices we haven't seen yet are fetched in full. Everything after that arrives as
+    // property changes.
+    if !context.update_devices(adapter, |devices| devices.contains(&address))
+        && let Ok(device) = context.backend.device(adapter, address).await
+    {
+        context.update_devices(adapter, |devices| devices.insert(device));
+    }
+}
+
+async fn listen_for_device_changes(context: BTContext, adapter: String) {
+    let mut count = 0;
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    // Unlike `discover_devices_with_changes`, this doesn't start discovery. Devices still show up
+    // here while a scan started with `Action::StartScan` is running.
+    let mut stream = loop {
+        if let Ok(stream) = context.backend.adapter_events(&adapter).await {
+            break stream;
+        };
+
+        interval.tick().await;
+
+        if count > 10 {
+            return;
+        }
+
+        count += 1;
+    };
+
+    let mut watches = DeviceWatches::default();
+
+    // The devices are fetched once, here or for an earlier state, and only listened to after.
+    let (paired_devices, available_devices, blocked_devices) =
+        context.devices(&adapter, None).await;
+
+    for device in paired_devices
+        .iter()
+        .chain(&available_devices)
+        .chain(&blocked_devices)
+    {
+        watches.watch(&context, &adapter, device.address).await;
+    }
+
+    context.publish();
+
+    loop {
+        tokio::select! {
+            event = stream.next() => match event {
+                Some(AdapterEvent::DeviceAdded(address)) => {
+                    watch_device(&context, &adapter, &mut watches, address).await;
+                    context.update_devices(&adapter, |devices| devices.seen(&address));
+                }
+                Some(AdapterEvent::DeviceRemoved(address)) => {
+                    watches.unwatch(&address);
+                    context.update_devices(&adapter, |devices| devices.remove(&address));
+                }
+                // Adapter properties are handled by `listen_for_adapter_property_changes`.
+                Some(AdapterEvent::PropertyChanged(_)) => continue,
+                None => break,
+            },
+            Some((address, DeviceEvent::PropertyChanged(property))) = watches.changes.next(), if !watches.changes.is_empty() => {
+                if let DeviceProperty::Connected(connected) = property {
+                    context.forget_audio_cards();
+
+                    if connected {
+                        let context = context.clone();
+                        tokio::spawn(async move {
+                            tokio::time::sleep(Duration::from_millis(AUDIO_CARD_DELAY_MS)).await;
+                            context.forget_audio_cards();
+                            context.publish();
+                        });
+                    }
+                }
+
+                if !context.update_devices(&adapter, |devices| devices.apply(&address, property)) {
+                    continue;
+                }
+            }
+        }
+
+        context.publish();
+    }
+}
+
+fn spawn_adapter_listeners(context: &BTContext, adapter: String) -> Vec<JoinHandle<()>> {
+    vec![
+        tokio::spawn(listen_for_device_changes(context.clone(), adapter.clone())),
+        tokio::spawn(listen_for_adapter_property_changes(
+            context.clone(),
+            adapter,
+        )),
+    ]
+}
+
+async fn listen_for_adapter_changes(context: BTContext) {
+    let mut stream = match context.backend.events().await {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!("Failed to listen for bluetooth adapter changes. {e:?}");
+            return;
+        }
+    };
+
+    let mut listeners = HashMap::<String, Vec<JoinHandle<()>>>::new();
+
+    // Adapters that were already plugged in don't show up as events.
+    for name in context.backend.adapter_names().await.unwrap_or_default() {
+        listeners.insert(name.clone(), spawn_adapter_listeners(&context, name));
+    }
+
+    while let Some(event) = stream.next().await {
+        match event {
+            SessionEvent::AdapterAdded(name) => {
+                info!("Bluetooth adapter {} added", name);
+
+                let handles = spawn_adapter_listeners(&context, name.clone());
+
+                if let Some(old_handles) = listeners.insert(name, handles) {
+                    old_handles.iter().for_each(JoinHandle::abort);
+                }
+            }
+            SessionEvent::AdapterRemoved(name) => {
+                info!("Bluetooth adapter {} removed", name);
+
+                if let Some(handles) = listeners.remove(&name) {
+                    handles.iter().for_each(JoinHandle::abort);
+                }
+
+                context.forget_adapter(&name);
+            }
+        }
+
+        context.publish();
+    }
+}
+
+pub async fn init_bluetooth(app_tx: Sender<AppEvent>) -> Result<Sender<BTEvent>> {
+    // FROM: https://github.com/pop-os/cosmic-applets/blob/c171f048a6dff1a032eb5edf8f343cac60971ac5/cosmic-applet-bluetooth/src/bluetooth.rs#L82,L97
+    //
+    // ChatGPT says this code is attempting to establish a session with retry logic, using exponential backoff.
+    // - 2_u64 is just the literal integer (i32) 2, but instucts the compiler treat it as a u64.
+    //   saturating_pow is implemented for u64 and not i32. the rust compiler would assume the
+    //   integer was i32 if it was not explicitly specified.
+    // - u32 is the same as u64 but is a smaller integer type.
+    // - each iteration of the loop adds 1 to the retry_count, meaning the result of 2^retry_count
+    //   increases exponentially in each iteration.
+    // - 2^retry_count (exponential growth)
+    // - they use .saturating_pow(u32) instead of .pow(u32) to avoid overflowing. it returns the
+    //   max or min bound when the result is too large to fit in the return type.
+    // - 68_719_476_734 is the time in milliseconds. This is actially 2.18 years.
+    // - the original code chained `.max(68719476734)` on to the result of the pow call, but
+    //   ChatGPT (which I also agree after looking at the docs) says that it's possibly a
+    //   mistake/bug. `.max(68719476734)` compares the result of the pow call to 68719476734,
+    //   and returns the larger of the two. Meaning, on the first iteration, the result of the
+    //   pow call will be 2, which is less than 68719476734, so 68719476734 will be returned.
+    //   Therefore, the loop will wait for 2 years before retrying!
+    //   I have created a [PR](https://github.com/pop-os/cosmic-applets/issues/997) to fix this.
+    // - ChatGPT suggest that `.min(68719476734)` should be used instead of `.max(68719476734)`.
+    //   This will enable the exponential backoff to work correctly as it will exponentially
+    //   increase from 2 to 68719476734.
+
+    let mut retry_count = 0u32;
+    let max_retries = config::current().bluetooth.session_retries;
+
+    // Initialize connection.
+    let session = loop {
+        if let Ok(session) = Session::new().await {
+            break session;
+        }
+
+        // will run up to retry_count = 16 (the default) which 65,536 milliseconds which is roughly 1.1 seconds.
+        if retry_count >= max_retries {
+            anyhow::bail!("Failed to connect to Bluetooth session");
+        }
+
+        retry_count = retry_count.saturating_add(1);
+        _ = tokio::time::sleep(Duration::from_millis(
+            2_u64.saturating_pow(retry_count).min(65_536),
+        ))
+        .await;
+    };
+
+    // Without an agent of our own, pairing devices that need a PIN or passkey fails.
+    let agent = match register_agent(&session, app_tx.clone()).await {
+        Ok(handle) => Some(handle),
+        Err(e) => {
+            error!("Failed to register bluetooth agent. {e:?}");
+            None
+        }
+    };
+
+    let context = BTContext::new(
+        app_tx,
+        Arc::new(Bluer::new(session)),
+        Favourites::load(),
+        Arc::new(Pactl),
+    )
+    .await;
+
+    tokio::spawn(listen_for_resume(context.clone()));
+
+    start(context, agent).await
+}
+
+/// Publishes the first state, starts listening for changes and runs requests against the
+/// context's backend.
+async fn start(context: BTContext, agent: Option<AgentHandle>) -> Result<Sender<BTEvent>> {
+    let (tx, mut rx) = channel::<BTEvent>(32);
+    let app_tx = context.app_tx.clone();
+
+    let state = context.build_state().await?;
+
+    tx.send(BTEvent::Init(state)).await?;
+
+    tokio::spawn(publish_state(context.clone()));
+
+    tokio::spawn(listen_for_adapter_changes(context.clone()));
+
+    tokio::spawn(listen_for_rfkill_changes(context.clone()));
+
+    reconnect_all_favourites(&context).await;
+
+    tokio::spawn(async move {
+        // The agent is unregistered when its handle is dropped.
+        let _agent = agent;
+
+        while let Some(action) = rx.recv().await {
+            match action {
+                BTEvent::Init(btstate) => {
+                    if let Err(e) = app_tx.send(AppEvent::Response(btstate)).await {
+                        error!("Failed to send BTState to AppEvent::Response: {e}");
+                    };
+                }
+                BTEvent::Request {
+                    action,
+                    state,
+                    reply,
+                } => {
+                    // `App` shows progress for these until it hears back.
+                    let finished = action.operation().map(|(address, _)| address);
+
+                    if let Action::SelectAdapter(name) = action {
+                        context.select_adapter(Some(name));
+                        context.publish();
+                        send_reply(reply, Ok(()));
+                        continue;
+                    }
+
+                    // Works without a bluetooth adapter, e.g. to turn wifi back on.
+                    if let Action::ToggleAirplaneMode = action {
+                        let result =
+                            toggle_airplane_mode(&*context.backend, state.airplane_mode).await;
+                        report_result(&app_tx, result, reply).await;
+                        continue;
+                    }
+
+                    let backend = &*context.backend;
+                    let Some(adapter_state) = state.adapter() else {
+                        error!("No bluetooth adapter available for {action:?}");
+
+                        send_reply(
+                            reply,
+                            Err(BTFailure {
+                                operation: "use bluetooth".to_string(),
+                                error: BTError::Other("no adapter was found".to_string()),
+                            }),
+                        );
+
+                        if let Some(address) = finished {
+                            let _ = app_tx.send(AppEvent::Finished(address)).await;
+                        }
+
+                        continue;
+                    };
+
+                    // Connecting or pairing can take a while, so it's left to run on its own rather
+                    // than holding up every other request.
+                    if let Some(address) = finished {
+                        tokio::spawn(run_device_operation(
+                            context.clone(),
+                            adapter_state.name.clone(),
+                            action,
+                            address,
+                            reply,
+                        ));
+                        continue;
+                    }
+
+                    let adapter = adapter_state.name.as_str();
+
+                    let result = match action {
+                        Action::SelectAdapter(_)
+                        | Action::ToggleAirplaneMode
+                        | Action::ToggleDevice(_)
+                        | Action::PairDevice(_) => unreachable!(),
+                        Action::ToggleBluetooth => {
+                            let result = toggle_bluetooth(backend, adapter_state).await;
+
+                            // There's a significant delay when turning off the adapter. Borrowing some ideas from GNOME's
+                            // bluetooth applet.
+                            // FROM: https://github.com/GNOME/gnome-shell/blob/4272916830120c0ff858e9b9de5d242a04932632/js/ui/status/bluetooth.js#L123-L140
+                            let context = context.clone();
+                            tokio::spawn(async move {
+                                let retry_ms = config::current().bluetooth.state_changed_retry_ms;
+                                tokio::time::sleep(Duration::from_millis(retry_ms)).await;
+
+                                context.publish();
+                            });
+
+                            result
+                        }
+                        Action::ToggleDiscoverable => {
+                            toggle_mode(backend, adapter_state, AdapterMode::Discoverable).await
+                        }
+                        Action::TogglePairable => {
+                            toggle_mode(backend, adapter_state, AdapterMode::Pairable).await
+                        }
+                        Action::ToggleTrust(device) => {
+                            trust_device(backend, adapter, &device).await
+                        }
+                        Action::ForgetDevice(address) => {
+                            let result = forget_device(backend, adapter_state, &address).await;
+
+                            if result.is_ok() {
+                                // BlueZ can take a moment to report the device as removed, so
+                                // make sure it's gone from the menu straight away.
+                                context.update_devices(adapter, |devices| devices.remove(&address));
+                                context.set_favourite(address, false).await;
+                            }
+
+                            result
+                        }
+                        Action::RenameDevice(address, name) => {
+                            rename_device(backend, adapter_state, address, name).await
+                        }
+                        Action::BlockDevice(address) => {
+                            block_device(backend, adapter_state, address, true).await
+                        }
+                        Action::UnblockDevice(address) => {
+                            block_device(backend, adapter_state, address, false).await
+                        }
+                        Action::SetAudioProfile(address, profile) => {
+                            let result = audio::switch_profile(&*context.audio, address, &profile)
+                                .await
+                                .map_err(|e| BTFailure {
+                                    operation: format!("switch to the {} profile", profile),
+                                    error: BTError::Other(e.to_string()),
+                                });
+
+                            context.forget_audio_cards();
+                            result
+                        }
+                        Action::ToggleFavourite(device) => {
+                            context
+                                .set_favourite(device.address, !device.is_favourite)
+                                .await;
+                            Ok(())
+                        }
+                        Action::StartScan => {
+                            context.start_scan(adapter);
+                            Ok(())
+                        }
+                        Action::StopScan => {
+                            if context.stop_scan(adapter) {
+                                Ok(())
+                            } else {
+                                // Scans started by other programs, e.g. bluetoothctl, are theirs
+                                // to stop.
+                                Err(BTFailure {
+                                    operation: "stop scanning".to_string(),
+                                    error: BTError::Other(
+                                        "the scan wasn't started from here".to_string(),
+                                    ),
+                                })
+                            }
+                        }
+                    };
+
+                    report_result(&app_tx, result, reply).await;
+
+                    context.publish();
+                }
+            }
+        }
+    });
+
+    Ok(tx)
+}
+
+async fn report_failure(app_tx: &Sender<AppEvent>, failure: BTFailure) {
+    error!("{failure}");
+
+    if let Err(e) = app_tx.send(AppEvent::Failure(failure)).await {
+        error!("Failed to send AppEvent::Failure: {e}");
+    }
+}
+
+fn send_reply(reply: Option<Reply>, result: Result<(), BTFailure>) {
+    if let Some(reply) = reply {
+        // The caller may have given up waiting.
+        let _ = reply.send(result);
+    }
+}
+
+/// Shows a failure in the tray, and hands the result to whoever made the request.
+async fn report_result(
+    app_tx: &Sender<AppEvent>,
+    result: Result<(), BTFailure>,
+    reply: Option<Reply>,
+) {
+    if let Err(failure) = &result {
+        report_failure(app_tx, failure.clone()).await;
+    }
+
+    send_reply(reply, result);
+}
+
+/// Connects, disconnects or pairs a device, then tells `App` it's done with it.
+async fn run_device_operation(
+    context: BTContext,
+    adapter: String,
+    action: Action,
+    address: Address,
+    reply: Option<Reply>,
+) {
+    let backend = &*context.backend;
+
+    let result = match action {
+        Action::ToggleDevice(device) => {
+            // Connecting or disconnecting a device by hand ends any attempt to reconnect it.
+            context.reconnects.lock().unwrap().remove(&device.address);
+
+            let operation = if device.is_on() {
+                "disconnect"
+            } else {
+                "connect"
+            };
+            with_timeout(
+                operation,
+                &device,
+                toggle_device(backend, &adapter, &device),
+            )
+            .await
+        }
+        Action::PairDevice(device) => pair_device(backend, &adapter, &device).await,
+        action => unreachable!("{action:?} isn't a device operation"),
+    };
+
+    report_result(&context.app_tx, result, reply).await;
+
+    let _ = context.app_tx.send(AppEvent::Finished(address)).await;
+    context.publish();
+}
+
+/// Talks to BlueZ without the rest of the applet. Used by the command line client when the applet
+/// isn't running.
+pub struct Direct {
+    context: BTContext,
+}
+
+impl Direct {
+    pub async fn new() -> Result<Self> {
+        let backend: Arc<dyn BluetoothBackend> = Arc::new(Bluer::new(Session::new().await?));
+        // Nothing listens for published states here.
+        let (app_tx, _) = channel::<AppEvent>(1);
+
+        let context = BTContext::new(app_tx, backend, Favourites::load(), Arc::new(Pactl)).await;
+
+        Ok(Self { context })
+    }
+
+    pub async fn state(&self) -> Result<BTState> {
+        self.context.build_state_for(StateView::Cli).await
+    }
+
+    /// Runs `action` against the default adapter and returns the state after.
+    pub async fn run(&self, action: Action) -> Result<BTState> {
+        let context = &self.context;
+        let backend = &*context.backend;
+        let state = context.build_state_for(StateView::Cli).await?;
+
+        let Some(adapter_state) = state.adapter() else {
+            anyhow::bail!("No bluetooth adapter found");
+        };
+        let adapter = adapter_state.name.as_str();
+
+        let changed_device = match action {
+            Action::ToggleBluetooth => {
+                toggle_bluetooth(backend, adapter_state).await?;
+                self.wait_for_power(adapter, !adapter_state.on).await;
+                None
+            }
+            Action::ToggleDevice(device) => {
+                toggle_device(backend, adapter, &device).await?;
+                Some(device.address)
+            }
+            Action::PairDevice(device) => {
+                pair_device(backend, adapter, &device).await?;
+                Some(device.address)
+            }
+            Action::RenameDevice(address, name) => {
+                rename_device(backend, adapter_state, address, name).await?;
+                Some(address)
+            }
+            action => anyhow::bail!("{action:?} is only supported while the applet is running"),
+        };
+
+        // Nothing listens for property changes here, so the cached device is refreshed by hand.
+        if let Some(address) = changed_device
+            && let Ok(device) = backend.device(adapter, address).await
+        {
+            context.update_devices(adapter, |devices| devices.insert(device));
+        }
+
+        context.build_state_for(StateView::Cli).await
+    }
+
+    /// BlueZ can take a moment to power the adapter on or off after it's asked to.
+    async fn wait_for_power(&self, adapter: &str, on: bool) {
+        let timeout = Duration::from_millis(config::current().bluetooth.operation_timeout_ms);
+        let backend = &*self.context.backend;
+
+        let _ = tokio::time::timeout(timeout, async {
+            while backend.is_powered(adapter).await.ok() != Some(on) {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await;
+    }
+}
+
+async fn load_devices(backend: &dyn BluetoothBackend, adapter: &str) -> DeviceMap {
+    let addresses = backend.device_addresses(adapter).await.unwrap_or_default();
+
+    let mut devices = DeviceMap::default();
+
+    let mut device_stream = addresses
+        .into_iter()
+        .map(|address| backend.device(adapter, address))
+        .collect::<FuturesUnordered<_>>();
+
+    while let Some(device) = device_stream.next().await {
+        if let Ok(device) = device {
+            devices.insert(device)
+        }
+    }
+
+    devices
+}
+
+async fn build_adapter_state(
+    context: &BTContext,
+    adapter: &str,
+    view: StateView,
+) -> Result<BTAdapter> {
+    let backend = &context.backend;
+    let alias = backend
+        .alias(adapter)
+        .await
+        .unwrap_or_else(|_| adapter.to_string());
+    let on = backend.is_powered(adapter).await?;
+    let scanning = backend.is_discovering(adapter).await.unwrap_or_default();
+    let discoverable = backend.is_discoverable(adapter).await.unwrap_or_default();
+    let pairable = backend.is_pairable(adapter).await.unwrap_or_default();
+    let (mut paired_devices, available_devices, blocked_devices) =
+        context.devices(adapter, view.stale_after()).await;
+    let (soft_blocked, hard_blocked) = context
+        .rfkill
+        .lock()
+        .unwrap()
+        .device(adapter)
+        .map_or((false, false), |device| (device.soft, device.hard));
+
+    paired_devices
+        .iter_mut()
+        .for_each(|device| context.annotate(device));
+
+    Ok(BTAdapter {
+        name: adapter.to_string(),
+        alias,
+        on,
+        soft_blocked,
+        hard_blocked,
+        scanning,
+        scan_started_here: context.is_scanning(adapter),
+        discoverable,
+        pairable,
+        discoverable_until: context.deadline(adapter, AdapterMode::Discoverable),
+        pairable_until: context.deadline(adapter, AdapterMode::Pairable),
+        paired_devices,
+        available_devices,
+        blocked_devices,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::Receiver;
+
+    use super::*;
+    use crate::{
+        app::App,
+        audio::{AudioProfile, FakeAudio},
+        backend::fake::FakeBluetooth,
+        tray::TrayEvent,
+    };
+
+    const ADAPTER: &str = "hci0";
+
+    const STALE_AFTER: Duration = Duration::from_secs(60);
+
+    fn device(index: u8, is_paired: bool) -> BTDevice {
+        let address = Address::new([0, 0, 0, 0, 0, index]);
+
+        BTDevice {
+            name: format!("Device {}", index),
+            advertised_name: Some(format!("Device {}", index)),
+            address,
+            status: BTDevice::status(is_paired, false),
+            battery_percentage: None,
+            rssi: Some(-60),
+            tx_power: None,
+            last_seen: Some(Instant::now()),
+            is_paired,
+            is_trusted: false,
+            is_blocked: false,
+            kind: DeviceKind::Other,
+            is_favourite: false,
+            reconnect: None,
+            audio: None,
+        }
+    }
+
+    #[test]
+    fn property_changes_update_the_device_in_place() {
+        let mut devices = DeviceMap::default();
+        let address = device(1, true).address;
+        devices.insert(device(1, true));
+
+        assert!(devices.apply(&address, DeviceProperty::Connected(true)));
+        assert!(devices.apply(&address, DeviceProperty::BatteryPercentage(42)));
+        assert!(devices.apply(&address, DeviceProperty::Rssi(-40)));
+        assert!(!devices.apply(&address, DeviceProperty::ServicesResolved(true)));
+
+        let (paired_devices, available_devices, _) = devices.split(Some(STALE_AFTER));
+        assert!(available_devices.is_empty());
+        assert_eq!(paired_devices[0].status, BTDeviceStatus::Connected);
+        assert_eq!(paired_devices[0].battery_percentage, Some(42));
+        assert_eq!(paired_devices[0].rssi, Some(-40));
+    }
+
+    #[test]
+    fn advertised_names_are_followed_until_the_device_is_renamed() {
+        let mut device = device(1, true);
+
+        assert!(device.apply(DeviceProperty::Name("Headset".to_string())));
+        assert_eq!(device.name, "Headset");
+
+        // What BlueZ sends after a rename, and again after a later change of name.
+        device.apply(DeviceProperty::Alias("Work headset".to_string()));
+        device.apply(DeviceProperty::Name("Headset Pro".to_string()));
+        assert_eq!(device.name, "Work headset");
+
+        // Renaming to "" goes back to the advertised name.
+        device.apply(DeviceProperty::Alias("Headset Pro".to_string()));
+        device.apply(DeviceProperty::Name("Headset Max".to_string()));
+        assert_eq!(device.name, "Headset Max");
+    }
+
+    #[test]
+    fn pairing_moves_the_device_to_paired_devices() {
+        let mut devices = DeviceMap::default();
+        let address = device(1, false).address;
+        devices.insert(device(1, false));
+
+        assert_eq!(devices.split(Some(STALE_AFTER)).1.len(), 1);
+
+        devices.apply(&address, DeviceProperty::Paired(true));
+
+        let (paired_devices, available_devices, _) = devices.split(Some(STALE_AFTER));
+        assert_eq!(paired_devices.len(), 1);
+        assert!(available_devices.is_empty());
+    }
+
+    #[test]
+    fn blocked_devices_are_kept_apart_unless_paired() {
+        let mut devices = DeviceMap::default();
+        devices.insert(device(1, true));
+        devices.insert(device(2, false));
+
+        devices.apply(&device(1, true).address, DeviceProperty::Blocked(true));
+        devices.apply(&device(2, false).address, DeviceProperty::Blocked(true));
+
+        let (paired_devices, available_devices, blocked_devices) = devices.split(Some(STALE_AFTER));
+        assert!(paired_devices[0].is_blocked);
+        assert!(available_devices.is_empty());
+        assert_eq!(blocked_devices[0].address, device(2, false).address);
+    }
+
+    #[test]
+    fn removed_devices_are_dropped() {
+        let mut devices = DeviceMap::default();
+        let address = device(1, true).address;
+        devices.insert(device(1, true));
+
+        assert!(devices.remove(&address));
+        assert!(!devices.apply(&address, DeviceProperty::Connected(true)));
+        assert!(devices.split(Some(STALE_AFTER)).0.is_empty());
+    }
+
+    #[test]
+    fn available_devices_are_sorted_by_signal_and_go_stale() {
+        let mut devices = DeviceMap::default();
+
+        for (index, rssi) in [(1, -80), (2, -40), (3, -60)] {
+            devices.insert(BTDevice {
+                rssi: Some(rssi),
+                ..device(index, false)
+            });
+        }
+
+        devices.insert(BTDevice {
+            last_seen: Instant::now().checked_sub(STALE_AFTER),
+            ..device(4, false)
+        });
+        devices.insert(BTDevice {
+            rssi: None,
+            last_seen: None,
+            ..device(5, false)
+        });
+
+        let available_devices = devices.split(Some(STALE_AFTER)).1;
+        assert_eq!(
+            available_devices
+                .iter()
+                .map(|device| device.rssi)
+                .collect::<Vec<_>>(),
+            [Some(-40), Some(-60), Some(-80)]
+        );
+    }
+
+    #[test]
+    fn bluez_errors_are_mapped() {
+        let error = |kind, message: &str| bluer::Error {
+            kind,
+            message: message.to_string(),
+        };
+
+        assert_eq!(
+            BTError::from(error(ErrorKind::Failed, "br-connection-page-timeout")),
+            BTError::PageTimeout
+        );
+        assert_eq!(
+            BTError::from(error(ErrorKind::Failed, "Blocked through rfkill")),
+            BTError::RfkillBlocked
+        );
+        assert_eq!(
+            BTError::from(error(ErrorKind::AuthenticationRejected, "")),
+            BTError::AuthFailed
+        );
+        assert_eq!(
+            BTError::from(error(ErrorKind::Failed, "Input/output error")),
+            BTError::Other("Input/output error".to_string())
+        );
+        // Only the message says whether the device didn't answer.
+        assert_eq!(
+            BTError::from(error(ErrorKind::ConnectionAttemptFailed, "")),
+            BTError::Other(ErrorKind::ConnectionAttemptFailed.to_string())
+        );
+    }
+
+    #[test]
+    fn reconnect_backoff_is_bounded() {
+        assert_eq!(reconnect_delay(2_000, 1), Duration::from_secs(2));
+        assert_eq!(reconnect_delay(2_000, 3), Duration::from_secs(8));
+        assert_eq!(
+            reconnect_delay(2_000, 40),
+            Duration::from_millis(RECONNECT_MAX_DELAY_MS)
+        );
+    }
+
+    /// Runs the request loop against a fake BlueZ, the way `App` would.
+    struct Harness {
+        fake: Arc<FakeBluetooth>,
+        audio: Arc<FakeAudio>,
+        bt_tx: Sender<BTEvent>,
+        app_rx: Receiver<AppEvent>,
+        state: BTState,
+    }
+
+    impl Harness {
+        async fn start(fake: FakeBluetooth) -> Self {
+            Self::start_with_audio(fake, FakeAudio::default()).await
+        }
+
+        async fn start_with_audio(fake: FakeBluetooth, audio: FakeAudio) -> Self {
+            let fake = Arc::new(fake);
+            let audio = Arc::new(audio);
+            let has_adapters = !fake.adapter_names().await.unwrap().is_empty();
+            let (app_tx, app_rx) = channel(64);
+            let context =
+                BTContext::new(app_tx, fake.clone(), Favourites::default(), audio.clone()).await;
+
+            let mut harness = Self {
+                fake,
+                audio,
+                bt_tx: start(context, None).await.unwrap(),
+                app_rx,
+                state: BTState::default(),
+            };
+
+            harness.state_where(|_| true).await;
+
+            // The next state is published once the device listeners are in place, so that changes
+            // made from here on aren't missed.
+            if has_adapters {
+                harness.state_where(|_| true).await;
+            }
+
+            harness
+        }
+
+        async fn next(&mut self) -> AppEvent {
+            tokio::time::timeout(Duration::from_secs(5), self.app_rx.recv())
+                .await
+                .expect("timed out waiting for the bluetooth task")
+                .unwrap()
+        }
+
+        async fn request(&self, action: Action) {
+            self.bt_tx
+                .send(BTEvent::Request {
+                    action,
+                    state: self.state.clone(),
+                    reply: None,
+                })
+                .await
+                .unwrap();
+        }
+
+        /// Makes a request the way a D-Bus caller would, waiting for its result.
+        async fn call(&self, action: Action) -> oneshot::Receiver<Result<(), BTFailure>> {
+            let (reply_tx, reply_rx) = oneshot::channel();
+
+            self.bt_tx
+                .send(BTEvent::Request {
+                    action,
+                    state: self.state.clone(),
+                    reply: Some(reply_tx),
+                })
+                .await
+                .unwrap();
+
+            reply_rx
+        }
+
+        async fn state_where(&mut self, f: impl Fn(&BTState) -> bool) -> BTState {
+            loop {
+                if let AppEvent::Response(state) = self.next().await
+                    && f(&state)
+                {
+                    self.state = state.clone();
+                    return state;
+                }
+            }
+        }
+
+        async fn failure(&mut self) -> BTFailure {
+            loop {
+                if let AppEvent::Failure(failure) = self.next().await {
+                    return failure;
+                }
+            }
+        }
+
+        async fn finished(&mut self) -> Address {
+            loop {
+                if let AppEvent::Finished(address) = self.next().await {
+                    return address;
+                }
+            }
+        }
+    }
+
+    fn adapter(state: &BTState) -> &BTAdapter {
+        state.adapter().unwrap()
+    }
+
+    fn fake_with_devices(devices: impl IntoIterator<Item = BTDevice>) -> FakeBluetooth {
+        let fake = FakeBluetooth::default();
+        fake.add_adapter(ADAPTER, true);
+
+        for device in devices {
+            fake.add_device(ADAPTER, device);
+        }
+
+        fake
+    }
+
+    #[tokio::test]
+    async fn connecting_a_device_finishes_and_publishes_it() {
+        let device = device(1, true);
+        let mut harness = Harness::start(fake_with_devices([device.clone()])).await;
+
+        harness.request(Action::ToggleDevice(device.clone())).await;
+
+        assert_eq!(harness.finished().await, device.address);
+        harness
+            .state_where(|state| adapter(state).paired_devices[0].is_on())
+            .await;
+        assert_eq!(harness.fake.calls(), ["connect 00:00:00:00:00:01"]);
+    }
+
+    #[tokio::test]
+    async fn failures_are_reported_before_finishing() {
+        let device = device(1, true);
+        let fake = fake_with_devices([device.clone()]);
+        fake.fail(
+            "connect",
+            bluer::Error {
+                kind: ErrorKind::Failed,
+                message: "br-connection-page-timeout".to_string(),
+            },
+        );
+        let mut harness = Harness::start(fake).await;
+
+        harness.request(Action::ToggleDevice(device.clone())).await;
+
+        assert_eq!(
+            harness.failure().await,
+            BTFailure {
+                operation: "connect Device 1".to_string(),
+                error: BTError::PageTimeout,
+            }
+        );
+        assert_eq!(harness.finished().await, device.address);
+    }
+
+    #[tokio::test]
+    async fn callers_get_the_result() {
+        let connecting = device(1, true);
+        let failing = device(2, true);
+        let fake = fake_with_devices([connecting.clone(), failing.clone()]);
+        let harness = Harness::start(fake).await;
+
+        let reply = harness.call(Action::ToggleDevice(connecting)).await;
+        assert_eq!(reply.await.unwrap(), Ok(()));
+
+        harness.fake.fail(
+            "connect",
+            bluer::Error {
+                kind: ErrorKind::Failed,
+                message: "br-connection-page-timeout".to_string(),
+            },
+        );
+        let reply = harness.call(Action::ToggleDevice(failing)).await;
+        assert_eq!(
+            reply.await.unwrap(),
+            Err(BTFailure {
+                operation: "connect Device 2".to_string(),
+                error: BTError::PageTimeout,
+            })
+        );
+
+        let reply = harness.call(Action::StopScan).await;
+        assert_eq!(reply.await.unwrap().unwrap_err().operation, "stop scanning");
+    }
+
+    #[tokio::test]
+    async fn pairing_trusts_and_connects() {
+        let device = device(2, false);
+        let mut harness = Harness::start(fake_with_devices([device.clone()])).await;
+
+        harness.request(Action::PairDevice(device.clone())).await;
+
+        let state = harness
+            .state_where(|state| {
+                adapter(state)
+                    .paired_devices
+                    .first()
+                    .is_some_and(BTDevice::is_on)
+            })
+            .await;
+
+        assert!(adapter(&state).paired_devices[0].is_trusted);
+        assert!(adapter(&state).available_devices.is_empty());
+        assert_eq!(
+            harness.fake.calls(),
+            [
+                "pair 00:00:00:00:00:02",
+                "set_trusted 00:00:00:00:00:02",
+                "connect 00:00:00:00:00:02"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn the_cli_sees_every_device_without_sound_cards() {
+        let connected = BTDevice {
+            status: BTDevice::status(true, true),
+            ..device(1, true)
+        };
+        let cached = BTDevice {
+            rssi: None,
+            last_seen: None,
+            ..device(2, false)
+        };
+        let fake = Arc::new(fake_with_devices([connected, cached.clone()]));
+        let audio = Arc::new(FakeAudio::default());
+        let (app_tx, _app_rx) = channel(64);
+        let context = BTContext::new(app_tx, fake, Favourites::default(), audio.clone()).await;
+
+        let state = context.build_state_for(StateView::Cli).await.unwrap();
+        assert_eq!(
+            adapter(&state)
+                .available_devices
+                .iter()
+                .map(|device| device.address)
+                .collect::<Vec<_>>(),
+            [cached.address]
+        );
+        assert_eq!(*audio.listings.lock().unwrap(), 0);
+
+        let state = context.build_state().await.unwrap();
+        assert!(adapter(&state).available_devices.is_empty());
+        assert_eq!(*audio.listings.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn reconnects_stop_once_the_device_isnt_wanted() {
+        let device = device(1, true);
+        let fake = Arc::new(fake_with_devices([device.clone()]));
+        let (app_tx, _app_rx) = channel(64);
+        let mut favourites = Favourites::default();
+        favourites.set(device.address, true);
+        let context = BTContext::new(
+            app_tx,
+            fake.clone(),
+            favourites,
+            Arc::new(FakeAudio::default()),
+        )
+        .await;
+
+        context.set_reconnect(device.address, Reconnect::Trying { attempt: 0 });
+        context
+            .favourites
+            .lock()
+            .unwrap()
+            .set(device.address, false);
+        reconnect_device(context.clone(), ADAPTER.to_string(), device.address).await;
+
+        assert!(fake.calls().is_empty());
+
+        // What connecting or disconnecting it from the menu does.
+        context.favourites.lock().unwrap().set(device.address, true);
+        context.reconnects.lock().unwrap().remove(&device.address);
+        reconnect_device(context.clone(), ADAPTER.to_string(), device.address).await;
+
+        assert!(fake.calls().is_empty());
+
+        context.set_reconnect(device.address, Reconnect::Trying { attempt: 0 });
+        reconnect_device(context.clone(), ADAPTER.to_string(), device.address).await;
+
+        assert_eq!(fake.calls(), ["connect 00:00:00:00:00:01"]);
+    }
+
+    #[tokio::test]
+    async fn slow_connections_dont_hold_up_other_requests() {
+        let slow_device = device(1, true);
+        let other_device = device(2, true);
+        let fake = fake_with_devices([slow_device.clone(), other_device.clone()]);
+        fake.stall("connect");
+        let mut harness = Harness::start(fake).await;
+
+        harness.request(Action::ToggleDevice(slow_device)).await;
+        harness
+            .request(Action::RenameDevice(
+                other_device.address,
+                "Work headset".to_string(),
+            ))
+            .await;
+
+        harness
+            .state_where(|state| {
+                adapter(state)
+                    .paired_devices
+                    .iter()
+                    .any(|device| device.name == "Work headset")
+            })
+            .await;
+        assert_eq!(harness.fake.calls(), ["set_alias 00:00:00:00:00:02"]);
+    }
+
+    #[tokio::test]
+    async fn turning_bluetooth_off_also_soft_blocks_it() {
+        let mut harness = Harness::start(fake_with_devices([])).await;
+
+        harness.request(Action::ToggleBluetooth).await;
+
+        let state = harness
+            .state_where(|state| !adapter(state).on && adapter(state).soft_blocked)
+            .await;
+
+        assert!(!adapter(&state).hard_blocked);
+        assert_eq!(
+            harness.fake.calls(),
+            ["set_powered false", "set_soft_blocked hci0 true"]
+        );
+    }
+
+    #[tokio::test]
+    async fn power_failures_are_reported_even_when_rfkill_works() {
+        let fake = fake_with_devices([]);
+        fake.fail(
+            "set_powered",
+            bluer::Error {
+                kind: ErrorKind::Failed,
+                message: "Input/output error".to_string(),
+            },
+        );
+        let mut harness = Harness::start(fake).await;
+
+        harness.request(Action::ToggleBluetooth).await;
+
+        assert_eq!(
+            harness.failure().await,
+            BTFailure {
+                operation: "turn bluetooth off".to_string(),
+                error: BTError::Other("Input/output error".to_string()),
+            }
+        );
+        assert_eq!(
+            harness.fake.calls(),
+            ["set_powered false", "set_soft_blocked hci0 true"]
+        );
+    }
+
+    #[tokio::test]
+    async fn hard_blocked_adapters_are_left_alone() {
+        let mut harness = Harness::start(fake_with_devices([])).await;
+
+        harness.fake.set_rfkill(ADAPTER, false, true);
+        harness
+            .state_where(|state| adapter(state).hard_blocked)
+            .await;
+        harness.request(Action::ToggleBluetooth).await;
+
+        assert_eq!(harness.failure().await.error, BTError::RfkillBlocked);
+        assert!(harness.fake.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn forgotten_devices_disappear() {
+        let device = device(1, true);
+        let mut harness = Harness::start(fake_with_devices([device.clone()])).await;
+
+        harness.request(Action::ForgetDevice(device.address)).await;
+
+        harness
+            .state_where(|state| adapter(state).paired_devices.is_empty())
+            .await;
+        assert_eq!(harness.fake.calls(), ["remove_device 00:00:00:00:00:01"]);
+    }
+
+    #[tokio::test]
+    async fn renamed_devices_show_their_alias() {
+        let device = device(1, true);
+        let mut harness = Harness::start(fake_with_devices([device.clone()])).await;
+
+        harness
+            .request(Action::RenameDevice(
+                device.address,
+                "Work headset".to_string(),
+            ))
+            .await;
+
+        harness
+            .state_where(|state| adapter(state).paired_devices[0].name == "Work headset")
+            .await;
+        assert_eq!(harness.fake.calls(), ["set_alias 00:00:00:00:00:01"]);
+    }
+
+    #[tokio::test]
+    async fn blocking_moves_the_device_to_blocked_devices() {
+        let device = device(1, false);
+        let mut harness = Harness::start(fake_with_devices([device.clone()])).await;
+
+        harness.request(Action::BlockDevice(device.address)).await;
+
+        let state = harness
+            .state_where(|state| !adapter(state).blocked_devices.is_empty())
+            .await;
+        assert!(adapter(&state).available_devices.is_empty());
+
+        harness.request(Action::UnblockDevice(device.address)).await;
+
+        harness
+            .state_where(|state| adapter(state).blocked_devices.is_empty())
+            .await;
+        assert_eq!(
+            harness.fake.calls(),
+            [
+                "set_blocked 00:00:00:00:00:01",
+                "set_blocked 00:00:00:00:00:01"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn block_failures_name_the_device() {
+        let device = device(1, true);
+        let fake = fake_with_devices([device.clone()]);
+        fake.fail(
+            "set_blocked",
+            bluer::Error {
+                kind: ErrorKind::Failed,
+                message: "Input/output error".to_string(),
+            },
+        );
+        let mut harness = Harness::start(fake).await;
+
+        harness.request(Action::BlockDevice(device.address)).await;
+
+        assert_eq!(harness.failure().await.operation, "block Device 1");
+    }
+
+    #[tokio::test]
+    async fn discoverable_mode_counts_down() {
+        let fake = FakeBluetooth::default();
+        fake.add_adapter(ADAPTER, true);
+        let mut harness = Harness::start(fake).await;
+
+        harness.request(Action::ToggleDiscoverable).await;
+
+        let state = harness
+            .state_where(|state| adapter(state).discoverable_until.is_some())
+            .await;
+        let left = adapter(&state).discoverable_until.unwrap() - Instant::now();
+        assert!(adapter(&state).discoverable);
+        assert!(left > Duration::from_secs(170) && left <= Duration::from_secs(180));
+
+        harness.request(Action::ToggleDiscoverable).await;
+
+        harness
+            .state_where(|state| !adapter(state).discoverable)
+            .await;
+        assert_eq!(
+            harness.fake.calls(),
+            ["set_discoverable true 180", "set_discoverable false 180"]
+        );
+    }
+
+    #[tokio::test]
+    async fn device_changes_are_published() {
+        let device = device(1, true);
+        let mut harness = Harness::start(fake_with_devices([device.clone()])).await;
+
+        harness.fake.change_device(
+            ADAPTER,
+            device.address,
+            DeviceProperty::BatteryPercentage(15),
+        );
+
+        harness
+            .state_where(|state| adapter(state).paired_devices[0].battery_percentage == Some(15))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn sound_cards_are_listed_only_when_they_could_have_changed() {
+        let device = BTDevice {
+            status: BTDevice::status(true, true),
+            ..device(1, true)
+        };
+        let audio = FakeAudio::default();
+        audio.cards.lock().unwrap().insert(
+            device.address,
+            AudioCard {
+                profiles: ["a2dp-sink", "headset-head-unit"]
+                    .map(|name| AudioProfile {
+                        name: name.to_string(),
+                        description: name.to_string(),
+                    })
+                    .to_vec(),
+                active_profile: Some("a2dp-sink".to_string()),
+            },
+        );
+        let mut harness =
+            Harness::start_with_audio(fake_with_devices([device.clone()]), audio).await;
+
+        for battery_percentage in [90, 80, 70] {
+            harness.fake.change_device(
+                ADAPTER,
+                device.address,
+                DeviceProperty::BatteryPercentage(battery_percentage),
+            );
+        }
+
+        let state = harness
+            .state_where(|state| adapter(state).paired_devices[0].battery_percentage == Some(70))
+            .await;
+        assert!(adapter(&state).paired_devices[0].audio.is_some());
+        assert_eq!(*harness.audio.listings.lock().unwrap(), 1);
+
+        harness
+            .request(Action::SetAudioProfile(
+                device.address,
+                "headset-head-unit".to_string(),
+            ))
+            .await;
+
+        harness
+            .state_where(|state| {
+                adapter(state).paired_devices[0]
+                    .audio
+                    .as_ref()
+                    .is_some_and(|card| card.active_profile.as_deref() == Some("headset-head-unit"))
+            })
+            .await;
+        // Once to check the profile exists and once for the state after switching.
+        assert_eq!(*harness.audio.listings.lock().unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn devices_are_fetched_once_while_the_listener_starts() {
+        let fake = Arc::new(fake_with_devices((0..10).map(|index| device(index, true))));
+        let (app_tx, _app_rx) = channel(64);
+        let context = BTContext::new(
+            app_tx,
+            fake.clone(),
+            Favourites::default(),
+            Arc::new(FakeAudio::default()),
+        )
+        .await;
+
+        let listener = tokio::spawn(listen_for_device_changes(
+            context.clone(),
+            ADAPTER.to_string(),
+        ));
+        context.build_state().await.unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while (0..10).any(|index| {
+                let address = Address::new([0, 0, 0, 0, 0, index]);
+                fake.device_listeners(ADAPTER, address) == 0
+            }) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the devices were never listened to");
+        listener.abort();
+
+        assert_eq!(fake.reads("device"), 10);
+    }
+
+    #[tokio::test]
+    async fn only_new_devices_are_fetched() {
+        let mut harness =
+            Harness::start(fake_with_devices((0..40).map(|index| device(index, true)))).await;
+
+        let listed = harness.fake.reads("device_addresses");
+        assert_eq!(harness.fake.reads("device"), 40);
+
+        // BlueZ announces known devices again, e.g. when a scan starts, and every change after
+        // that is a property change.
+        for round in 0..50u8 {
+            for index in 0..40 {
+                let address = Address::new([0, 0, 0, 0, 0, index]);
+                harness.fake.change_device(
+                    ADAPTER,
+                    address,
+                    DeviceProperty::Connected(round.is_multiple_of(2)),
+                );
+                harness.fake.add_device(ADAPTER, device(index, true));
+            }
+        }
+
+        for index in 0..40 {
+            let address = Address::new([0, 0, 0, 0, 0, index]);
+            harness
+                .fake
+                .change_device(ADAPTER, address, DeviceProperty::BatteryPercentage(15));
+        }
+
+        let new_device = device(40, true);
+        harness.fake.add_device(ADAPTER, new_device.clone());
+
+        harness
+            .state_where(|state| {
+                let devices = &adapter(state).paired_devices;
+                devices.len() == 41
+                    && devices
+                        .iter()
+                        .filter(|device| device.address != new_device.address)
+                        .all(|device| device.battery_percentage == Some(15))
+            })
+            .await;
+        assert_eq!(harness.fake.reads("device_addresses"), listed);
+        assert_eq!(harness.fake.reads("device"), 41);
+
+        for index in 0..=40 {
+            let address = Address::new([0, 0, 0, 0, 0, index]);
+            assert_eq!(harness.fake.device_listeners(ADAPTER, address), 1);
+        }
+
+        // A device that comes back after being forgotten is listened to again.
+        harness
+            .request(Action::ForgetDevice(new_device.address))
+            .await;
+        harness
+            .state_where(|state| adapter(state).paired_devices.len() == 40)
+            .await;
+        harness.fake.add_device(ADAPTER, new_device.clone());
+        harness
+            .state_where(|state| adapter(state).paired_devices.len() == 41)
+            .await;
+        assert_eq!(
+            harness.fake.device_listeners(ADAPTER, new_device.address),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn announced_devices_are_fresh_without_a_signal() {
+        let cached = BTDevice {
+            rssi: None,
+            last_seen: None,
+            ..device(1, false)
+        };
+        let found = BTDevice {
+            rssi: None,
+            last_seen: None,
+            ..device(2, false)
+        };
+        let mut harness = Harness::start(fake_with_devices([cached])).await;
+
+        assert!(adapter(&harness.state).available_devices.is_empty());
+
+        harness.fake.add_device(ADAPTER, found.clone());
+        let state = harness
+            .state_where(|state| !adapter(state).available_devices.is_empty())
+            .await;
+
+        assert_eq!(
+            adapter(&state)
+                .available_devices
+                .iter()
+                .map(|device| device.address)
+                .collect::<Vec<_>>(),
+            [found.address]
+        );
+    }
+
+    #[tokio::test]
+    async fn scans_are_kept_per_adapter() {
+        let fake = FakeBluetooth::default();
+        fake.add_adapter(ADAPTER, true);
+        fake.add_adapter("hci1", true);
+        let mut harness = Harness::start(fake).await;
+
+        harness.request(Action::StartScan).await;
+        harness
+            .state_where(|state| adapter(state).scan_started_here)
+            .await;
+
+        harness
+            .request(Action::SelectAdapter("hci1".to_string()))
+            .await;
+        harness
+            .state_where(|state| adapter(state).name == "hci1")
+            .await;
+        assert!(!adapter(&harness.state).scan_started_here);
+
+        harness.request(Action::StartScan).await;
+        harness
+            .state_where(|state| adapter(state).scan_started_here)
+            .await;
+        assert_eq!(
+            harness.fake.calls(),
+            ["discover_devices hci0", "discover_devices hci1"]
+        );
+
+        harness.request(Action::StopScan).await;
+        harness
+            .state_where(|state| !adapter(state).scan_started_here)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn scans_started_elsewhere_are_not_ours_to_stop() {
+        let fake = FakeBluetooth::default();
+        fake.add_adapter(ADAPTER, true);
+        let mut harness = Harness::start(fake).await;
+
+        harness.request(Action::StopScan).await;
+
+        assert_eq!(harness.failure().await.operation, "stop scanning");
+    }
+
+    #[tokio::test]
+    async fn requests_without_an_adapter_still_finish() {
+        let device = device(1, true);
+        let mut harness = Harness::start(FakeBluetooth::default()).await;
+
+        assert!(harness.state.adapters.is_empty());
+
+        // The app between the tray and us, with nothing behind it but the harness.
+        let mut app = App::new();
+        let app_tx = app.get_sender();
+        let (tray_tx, mut tray_rx) = channel(8);
+        let (control_tx, _control_rx) = channel(8);
+        let (notification_tx, _notification_rx) = channel(8);
+        let (bt_tx, _bt_rx) = channel(8);
+        tokio::spawn(async move { app.run(tray_tx, control_tx, notification_tx, bt_tx).await });
+
+        app_tx
+            .send(AppEvent::Request(Action::ToggleDevice(device.clone())))
+            .await
+            .unwrap();
+        let Some(TrayEvent::Update(view)) = tray_rx.recv().await else {
+            panic!("the tray wasn't updated");
+        };
+        assert!(view.operations.contains_key(&device.address));
+
+        harness.request(Action::ToggleDevice(device.clone())).await;
+
+        assert_eq!(harness.finished().await, device.address);
+
+        app_tx
+            .send(AppEvent::Finished(device.address))
+            .await
+            .unwrap();
+        let Some(TrayEvent::Update(view)) = tray_rx.recv().await else {
+            panic!("the tray wasn't updated");
+        };
+        assert!(view.operations.is_empty());
+    }
 }
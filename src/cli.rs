@@ -0,0 +1,502 @@
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use futures::StreamExt;
+use zbus::Connection;
+
+use crate::{
+    bluetooth::{Action, Direct},
+    control::{ControlClientProxy, DeviceInfo, StateInfo},
+};
+
+const USAGE: &str = "Usage: bt-notsports [COMMAND]
+
+Starts the tray applet when no command is given.
+
+Commands:
+  status [--json]                   Show whether bluetooth is on and what is connected
+  list [--json]                     List known devices
+  power on|off|toggle               Power the bluetooth adapter on or off
+  connect <address|name>            Connect a device
+  disconnect [<address|name>]       Disconnect a device, or every connected device
+  rename <address|name> <new name>  Rename a device, or reset its name with \"\"
+  help                              Show this message
+
+Power, connect and disconnect wait for the change to happen, and fail if it doesn't.";
+
+/// The first arguments that run the command line client instead of the applet.
+const COMMANDS: [&str; 9] = [
+    "status",
+    "list",
+    "power",
+    "connect",
+    "disconnect",
+    "rename",
+    "help",
+    "--help",
+    "-h",
+];
+
+/// How long to wait for the applet to publish a change it has made. It only publishes states a
+/// few times a second, but turning the adapter off can take a few seconds to show.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Power {
+    On,
+    Off,
+    Toggle,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Status { json: bool },
+    List { json: bool },
+    Power(Power),
+    Connect(String),
+    Disconnect(Option<String>),
+    Rename(String, String),
+    Help,
+}
+
+impl Command {
+    /// Parses the arguments following the program name. Returns `None` when they don't start with
+    /// a command, meaning the applet itself should be started. Desktop files and autostart entries
+    /// may pass arguments of their own, which mustn't keep the applet from starting.
+    pub fn parse(args: &[String]) -> Result<Option<Command>> {
+        let Some((command, rest)) = args.split_first() else {
+            return Ok(None);
+        };
+
+        if !COMMANDS.contains(&command.as_str()) {
+            return Ok(None);
+        }
+
+        let rest = rest.iter().map(String::as_str).collect::<Vec<_>>();
+
+        let command = match (command.as_str(), rest.as_slice()) {
+            ("status", []) => Command::Status { json: false },
+            ("status", ["--json"]) => Command::Status { json: true },
+            ("list", []) => Command::List { json: false },
+            ("list", ["--json"]) => Command::List { json: true },
+            ("power", ["on"]) => Command::Power(Power::On),
+            ("power", ["off"]) => Command::Power(Power::Off),
+            ("power", ["toggle"]) => Command::Power(Power::Toggle),
+            ("connect", [device]) => Command::Connect(device.to_string()),
+            ("disconnect", []) => Command::Disconnect(None),
+            ("disconnect", [device]) => Command::Disconnect(Some(device.to_string())),
+            ("rename", [device, name]) => Command::Rename(device.to_string(), name.to_string()),
+            ("help" | "--help" | "-h", _) => Command::Help,
+            _ => bail!("Invalid command: {}\n\n{}", args.join(" "), USAGE),
+        };
+
+        Ok(Some(command))
+    }
+}
+
+/// Talks to the running applet over D-Bus, or straight to BlueZ when the applet isn't running.
+enum Client {
+    Daemon(ControlClientProxy<'static>),
+    Direct(Direct),
+}
+
+impl Client {
+    async fn new(daemon_running: bool) -> Result<Self> {
+        if daemon_running {
+            let connection = Connection::session().await?;
+            let proxy = ControlClientProxy::new(&connection).await?;
+            return Ok(Client::Daemon(proxy));
+        }
+
+        Ok(Client::Direct(Direct::new().await?))
+    }
+
+    async fn state(&self) -> Result<StateInfo> {
+        match self {
+            Client::Daemon(proxy) => Ok(proxy.get_state().await?),
+            Client::Direct(direct) => Ok(StateInfo::from(&direct.state().await?)),
+        }
+    }
+
+    async fn toggle_bluetooth(&self, on: bool) -> Result<()> {
+        let done = |state: &StateInfo| state.on == on;
+
+        let changed = match self {
+            Client::Daemon(proxy) => wait_for(proxy, proxy.toggle_bluetooth(), done).await?,
+            Client::Direct(direct) => done(&StateInfo::from(
+                &direct.run(Action::ToggleBluetooth).await?,
+            )),
+        };
+
+        if !changed {
+            bail!("Failed to turn bluetooth {}", if on { "on" } else { "off" });
+        }
+
+        Ok(())
+    }
+
+    async fn set_connected(&self, device: &DeviceInfo, connected: bool) -> Result<()> {
+        if device.connected == connected {
+            return Ok(());
+        }
+
+        let done = |state: &StateInfo| {
+            find_device(state, &device.address).is_ok_and(|device| device.connected == connected)
+        };
+        let failed = || {
+            anyhow::anyhow!(
+                "Failed to {} {}",
+                if connected { "connect" } else { "disconnect" },
+                device.name
+            )
+        };
+
+        match self {
+            Client::Daemon(proxy) => {
+                let changed = if connected {
+                    wait_for(proxy, proxy.connect(&device.address), done).await?
+                } else {
+                    wait_for(proxy, proxy.disconnect(&device.address), done).await?
+                };
+
+                if !changed {
+                    return Err(failed());
+                }
+
+                Ok(())
+            }
+            Client::Direct(direct) => {
+                let state = direct.state().await?;
+                let Some(bt_device) = state.adapter().and_then(|adapter| {
+                    adapter
+                        .paired_devices
+                        .iter()
+                        .chain(&adapter.available_devices)
+                        .find(|bt_device| bt_device.address.to_string() == device.address)
+                }) else {
+                    bail!("Unknown device {}", device.address);
+                };
+
+                let state =
+                    StateInfo::from(&direct.run(Action::ToggleDevice(bt_device.clone())).await?);
+
+                if !done(&state) {
+                    return Err(failed());
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    async fn rename(&self, device: &DeviceInfo, name: &str) -> Result<()> {
+        match self {
+            Client::Daemon(proxy) => Ok(proxy.rename(&device.address, name).await?),
+            Client::Direct(direct) => {
+                let address = device.address.parse()?;
+                direct
+                    .run(Action::RenameDevice(address, name.trim().to_string()))
+                    .await?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Makes a request to the applet and waits for it to report a state that `done` accepts. The
+/// applet's methods return once the request is carried out, or with the reason it failed, but the
+/// state that shows the change is published a moment later.
+async fn wait_for(
+    proxy: &ControlClientProxy<'_>,
+    request: impl Future<Output = zbus::Result<()>>,
+    done: impl Fn(&StateInfo) -> bool,
+) -> Result<bool> {
+    // Listening first, so that a change made right away isn't missed.
+    let mut changes = proxy.receive_state_changed().await?;
+
+    request.await?;
+
+    if done(&proxy.get_state().await?) {
+        return Ok(true);
+    }
+
+    let changed = async {
+        while let Some(change) = changes.next().await {
+            if change.args().is_ok_and(|args| done(&args.state)) {
+                return true;
+            }
+        }
+
+        false
+    };
+
+    Ok(tokio::time::timeout(REQUEST_TIMEOUT, changed)
+        .await
+        .unwrap_or_default())
+}
+
+/// Finds a device by address or, failing that, by a case-insensitive match on its name.
+fn find_device<'a>(state: &'a StateInfo, query: &str) -> Result<&'a DeviceInfo> {
+    if let Some(device) = state
+        .devices
+        .iter()
+        .find(|device| device.address.eq_ignore_ascii_case(query))
+    {
+        return Ok(device);
+    }
+
+    let query = query.to_lowercase();
+
+    if let Some(device) = state
+        .devices
+        .iter()
+        .find(|device| device.name.to_lowercase() == query)
+    {
+        return Ok(device);
+    }
+
+    let matches = state
+        .devices
+        .iter()
+        .filter(|device| device.name.to_lowercase().contains(&query))
+        .collect::<Vec<_>>();
+
+    match matches.as_slice() {
+        [device] => Ok(device),
+        [] => bail!("No device matches \"{}\"", query),
+        _ => bail!(
+            "\"{}\" matches more than one device: {}",
+            query,
+            matches
+                .iter()
+                .map(|device| device.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+fn device_label(device: &DeviceInfo) -> String {
+    if device.battery_percentage >= 0 {
+        format!("{} ({}%)", device.name, device.battery_percentage)
+    } else {
+        device.name.clone()
+    }
+}
+
+fn print_status(state: &StateInfo) {
+    if state.adapter.is_empty() {
+        println!("Bluetooth: no adapter found");
+        return;
+    }
+
+    let power = if state.hard_blocked {
+        "off, blocked by a hardware switch"
+    } else if state.soft_blocked {
+        "off, blocked by rfkill"
+    } else if state.on {
+        "on"
+    } else {
+        "off"
+    };
+
+    println!("Bluetooth: {} ({})", power, state.adapter);
+
+    let connected_devices = state
+        .devices
+        .iter()
+        .filter(|device| device.connected)
+        .map(device_label)
+        .collect::<Vec<_>>();
+
+    if connected_devices.is_empty() {
+        println!("No devices connected");
+    } else {
+        println!("Connected to: {}", connected_devices.join(", "));
+    }
+}
+
+fn print_devices(state: &StateInfo) {
+    for device in &state.devices {
+        let status = if device.connected {
+            "connected"
+        } else if device.paired {
+            "paired"
+        } else {
+            "available"
+        };
+
+        println!(
+            "{}  {:<10} {}",
+            device.address,
+            status,
+            device_label(device)
+        );
+    }
+}
+
+pub async fn run(command: Command, daemon_running: bool) -> Result<()> {
+    if command == Command::Help {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+
+    let client = Client::new(daemon_running).await?;
+    let state = client.state().await?;
+
+    match command {
+        Command::Status { json: true } => println!("{}", serde_json::to_string_pretty(&state)?),
+        Command::Status { json: false } => print_status(&state),
+        Command::List { json: true } => {
+            println!("{}", serde_json::to_string_pretty(&state.devices)?)
+        }
+        Command::List { json: false } => print_devices(&state),
+        Command::Power(power) => {
+            let on = match power {
+                Power::On => true,
+                Power::Off => false,
+                Power::Toggle => !state.on,
+            };
+
+            if on != state.on {
+                client.toggle_bluetooth(on).await?;
+            }
+        }
+        Command::Connect(query) => {
+            let device = find_device(&state, &query)?;
+            client.set_connected(device, true).await?;
+        }
+        Command::Disconnect(Some(query)) => {
+            let device = find_device(&state, &query)?;
+            client.set_connected(device, false).await?;
+        }
+        Command::Disconnect(None) => {
+            for device in state.devices.iter().filter(|device| device.connected) {
+                client.set_connected(device, false).await?;
+            }
+        }
+        Command::Rename(query, name) => {
+            let device = find_device(&state, &query)?;
+            client.rename(device, &name).await?;
+        }
+        Command::Help => unreachable!(),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Command>> {
+        Command::parse(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+    }
+
+    fn device(address: &str, name: &str) -> DeviceInfo {
+        DeviceInfo {
+            address: address.to_string(),
+            name: name.to_string(),
+            paired: true,
+            connected: false,
+            battery_percentage: -1,
+        }
+    }
+
+    fn state() -> StateInfo {
+        StateInfo {
+            devices: vec![
+                device("AA:BB:CC:DD:EE:01", "WH-1000XM4"),
+                device("AA:BB:CC:DD:EE:02", "Work headset"),
+                device("AA:BB:CC:DD:EE:03", "Work headset 2"),
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn commands_are_parsed() {
+        assert_eq!(parse(&[]).unwrap(), None);
+        assert_eq!(
+            parse(&["status", "--json"]).unwrap(),
+            Some(Command::Status { json: true })
+        );
+        assert_eq!(
+            parse(&["power", "toggle"]).unwrap(),
+            Some(Command::Power(Power::Toggle))
+        );
+        assert_eq!(
+            parse(&["disconnect"]).unwrap(),
+            Some(Command::Disconnect(None))
+        );
+        assert_eq!(
+            parse(&["rename", "headset", ""]).unwrap(),
+            Some(Command::Rename("headset".to_string(), String::new()))
+        );
+        assert_eq!(parse(&["-h"]).unwrap(), Some(Command::Help));
+    }
+
+    #[test]
+    fn invalid_commands_are_rejected() {
+        for args in [
+            &["connect"][..],
+            &["rename", "headset"],
+            &["power"],
+            &["power", "up"],
+            &["status", "--yaml"],
+            &["list", "--json", "extra"],
+        ] {
+            let error = parse(args).unwrap_err().to_string();
+            assert!(error.starts_with("Invalid command"), "{args:?}: {error}");
+            assert!(error.contains(USAGE), "{args:?}: {error}");
+        }
+    }
+
+    #[test]
+    fn unknown_arguments_start_the_applet() {
+        assert_eq!(parse(&["%U"]).unwrap(), None);
+        assert_eq!(parse(&["--foo", "status"]).unwrap(), None);
+        assert_eq!(parse(&["pair", "headset"]).unwrap(), None);
+    }
+
+    #[test]
+    fn devices_are_found_by_address_or_name() {
+        let state = state();
+
+        assert_eq!(
+            find_device(&state, "AA:BB:CC:DD:EE:01").unwrap().name,
+            "WH-1000XM4"
+        );
+        assert_eq!(
+            find_device(&state, "aa:bb:cc:dd:ee:02").unwrap().name,
+            "Work headset"
+        );
+        assert_eq!(
+            find_device(&state, "wh-1000xm4").unwrap().address,
+            "AA:BB:CC:DD:EE:01"
+        );
+        // An exact name wins over the names that contain it.
+        assert_eq!(
+            find_device(&state, "WORK HEADSET").unwrap().address,
+            "AA:BB:CC:DD:EE:02"
+        );
+        assert_eq!(
+            find_device(&state, "xm4").unwrap().address,
+            "AA:BB:CC:DD:EE:01"
+        );
+    }
+
+    #[test]
+    fn unknown_or_ambiguous_devices_are_rejected() {
+        let state = state();
+
+        assert_eq!(
+            find_device(&state, "Speaker").unwrap_err().to_string(),
+            "No device matches \"speaker\""
+        );
+        assert_eq!(
+            find_device(&state, "work").unwrap_err().to_string(),
+            "\"work\" matches more than one device: Work headset, Work headset 2"
+        );
+    }
+}
@@ -0,0 +1,450 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, LazyLock, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result, bail};
+use bluer::Address;
+use log::{LevelFilter, error, info};
+use serde::Deserialize;
+use tokio::sync::mpsc::Sender;
+
+use crate::{app::AppEvent, bluetooth::BTDevice};
+
+const CONFIG_DIR: &str = "bt-notsports";
+const CONFIG_FILE: &str = "config.toml";
+const CONFIG_POLL_MS: u64 = 2_000;
+
+static CONFIG: LazyLock<RwLock<Arc<Config>>> =
+    LazyLock::new(|| RwLock::new(Arc::new(Config::default())));
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub log_level: String,
+    pub bluetooth: BluetoothConfig,
+    pub tray: TrayConfig,
+    pub notifications: NotificationsConfig,
+    /// Keyed by device address.
+    pub devices: HashMap<String, DeviceConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BluetoothConfig {
+    /// How long to wait before checking the adapter again after toggling it.
+    pub state_changed_retry_ms: u64,
+    /// How often the adapter's power state is polled in case a D-Bus signal was missed.
+    pub power_poll_ms: u64,
+    /// How many times to retry connecting to bluetoothd on startup, with exponential backoff.
+    pub session_retries: u32,
+    pub scan_timeout_ms: u64,
+    /// Lower bound on the time between two menu updates.
+    pub publish_interval_ms: u64,
+    /// How long pairing and rename prompts stay open.
+    pub agent_prompt_timeout_ms: u64,
+    /// How many times a favourite device is tried before giving up on reconnecting it.
+    pub reconnect_attempts: u32,
+    /// Wait before the second attempt. It doubles with every attempt after that.
+    pub reconnect_backoff_ms: u64,
+    /// Available devices that haven't been found by a scan or reported their signal strength for
+    /// this long are hidden.
+    pub device_stale_ms: u64,
+    /// How long connecting or disconnecting a device may take before it's given up on. Pairing
+    /// gets `agent_prompt_timeout_ms` on top, since it can wait on a passkey being typed.
+    pub operation_timeout_ms: u64,
+    /// How long the adapter stays discoverable once it's made so from the menu. 0 keeps it
+    /// discoverable until it's turned off again.
+    pub discoverable_timeout_ms: u64,
+    /// The same for pairable mode.
+    pub pairable_timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrayConfig {
+    pub show_battery: bool,
+    pub show_available_devices: bool,
+    pub show_scan_item: bool,
+    /// Leaves out available devices that don't say what kind of device they are.
+    pub hide_unknown_devices: bool,
+    /// Adds an item that turns every radio off, not just bluetooth.
+    pub show_airplane_mode: bool,
+    pub icons: TrayIconsConfig,
+}
+
+/// PNG images to show instead of the icon theme's, one per state. States without an image
+/// follow the theme.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrayIconsConfig {
+    pub off: Option<PathBuf>,
+    pub on: Option<PathBuf>,
+    pub connected: Option<PathBuf>,
+    pub scanning: Option<PathBuf>,
+    pub blocked: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationsConfig {
+    pub connected: bool,
+    pub disconnected: bool,
+    pub power: bool,
+    pub low_battery: bool,
+    /// Battery levels, in percent, at which a low battery notification is shown.
+    pub battery_thresholds: Vec<u8>,
+    /// Minimum time between two notifications about the same thing.
+    pub min_interval_ms: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceConfig {
+    /// Shown in the menu instead of the name the device advertises.
+    pub name: Option<String>,
+    /// Leaves the device out of the menu altogether.
+    pub hidden: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            log_level: "info".to_string(),
+            bluetooth: BluetoothConfig::default(),
+            tray: TrayConfig::default(),
+            notifications: NotificationsConfig::default(),
+            devices: HashMap::new(),
+        }
+    }
+}
+
+impl Default for BluetoothConfig {
+    fn default() -> Self {
+        Self {
+            state_changed_retry_ms: 5_000,
+            power_poll_ms: 60_000,
+            session_retries: 16,
+            scan_timeout_ms: 30_000,
+            publish_interval_ms: 250,
+            agent_prompt_timeout_ms: 30_000,
+            reconnect_attempts: 5,
+            reconnect_backoff_ms: 2_000,
+            device_stale_ms: 60_000,
+            operation_timeout_ms: 30_000,
+            discoverable_timeout_ms: 180_000,
+            pairable_timeout_ms: 0,
+        }
+    }
+}
+
+impl Default for TrayConfig {
+    fn default() -> Self {
+        Self {
+            show_battery: true,
+            show_available_devices: true,
+            show_scan_item: true,
+            hide_unknown_devices: false,
+            show_airplane_mode: false,
+            icons: TrayIconsConfig::default(),
+        }
+    }
+}
+
+impl Default for NotificationsConfig {
+    fn default() -> Self {
+        Self {
+            connected: true,
+            disconnected: true,
+            power: false,
+            low_battery: true,
+            battery_thresholds: vec![20, 10],
+            min_interval_ms: 10_000,
+        }
+    }
+}
+
+impl Config {
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut config = toml::from_str::<Config>(contents)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&mut self) -> Result<()> {
+        if LevelFilter::from_str(&self.log_level).is_err() {
+            bail!(
+                "log_level must be one of off, error, warn, info, debug or trace, got \"{}\"",
+                self.log_level
+            );
+        }
+
+        let bluetooth = &self.bluetooth;
+
+        for (key, value, min) in [
+            (
+                "state_changed_retry_ms",
+                bluetooth.state_changed_retry_ms,
+                100,
+            ),
+            ("power_poll_ms", bluetooth.power_poll_ms, 1_000),
+            ("scan_timeout_ms", bluetooth.scan_timeout_ms, 1_000),
+            (
+                "agent_prompt_timeout_ms",
+                bluetooth.agent_prompt_timeout_ms,
+                1_000,
+            ),
+            ("reconnect_backoff_ms", bluetooth.reconnect_backoff_ms, 100),
+            ("device_stale_ms", bluetooth.device_stale_ms, 1_000),
+            (
+                "operation_timeout_ms",
+                bluetooth.operation_timeout_ms,
+                1_000,
+            ),
+        ] {
+            if value < min {
+                bail!("bluetooth.{} must be at least {}, got {}", key, min, value);
+            }
+        }
+
+        // BlueZ counts these in seconds.
+        for (key, value) in [
+            ("discoverable_timeout_ms", bluetooth.discoverable_timeout_ms),
+            ("pairable_timeout_ms", bluetooth.pairable_timeout_ms),
+        ] {
+            if value != 0 && value < 1_000 {
+                bail!(
+                    "bluetooth.{} must be 0 or at least 1000, got {}",
+                    key,
+                    value
+                );
+            }
+        }
+
+        if bluetooth.publish_interval_ms > 10_000 {
+            bail!(
+                "bluetooth.publish_interval_ms must be at most 10000, got {}",
+                bluetooth.publish_interval_ms
+            );
+        }
+
+        // 2^16 ms is already more than a minute between the last two attempts.
+        if bluetooth.session_retries > 16 {
+            bail!(
+                "bluetooth.session_retries must be at most 16, got {}",
+                bluetooth.session_retries
+            );
+        }
+
+        if let Some(threshold) = self
+            .notifications
+            .battery_thresholds
+            .iter()
+            .find(|threshold| !(1..=100).contains(*threshold))
+        {
+            bail!(
+                "notifications.battery_thresholds must be between 1 and 100, got {}",
+                threshold
+            );
+        }
+
+        // Auto-connect is turned off per device from the menu, not with 0 here.
+        if !(1..=20).contains(&bluetooth.reconnect_attempts) {
+            bail!(
+                "bluetooth.reconnect_attempts must be between 1 and 20, got {}",
+                bluetooth.reconnect_attempts
+            );
+        }
+
+        let icons = &self.tray.icons;
+
+        for (key, path) in [
+            ("off", &icons.off),
+            ("on", &icons.on),
+            ("connected", &icons.connected),
+            ("scanning", &icons.scanning),
+            ("blocked", &icons.blocked),
+        ] {
+            // The applet doesn't necessarily run from the directory the config is in.
+            if let Some(path) = path
+                && !path.is_absolute()
+            {
+                bail!(
+                    "tray.icons.{} must be an absolute path, got \"{}\"",
+                    key,
+                    path.display()
+                );
+            }
+        }
+
+        let mut devices = HashMap::with_capacity(self.devices.len());
+
+        for (key, device) in self.devices.drain() {
+            let address = Address::from_str(&key)
+                .map_err(|_| anyhow::anyhow!("devices.\"{}\" is not a bluetooth address", key))?;
+
+            if device
+                .name
+                .as_ref()
+                .is_some_and(|name| name.trim().is_empty())
+            {
+                bail!("devices.\"{}\".name must not be empty", key);
+            }
+
+            // Normalise the key so that lookups don't depend on how the address was written.
+            devices.insert(address.to_string(), device);
+        }
+
+        self.devices = devices;
+
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> LevelFilter {
+        LevelFilter::from_str(&self.log_level).unwrap_or(LevelFilter::Info)
+    }
+
+    pub fn device(&self, address: &Address) -> Option<&DeviceConfig> {
+        self.devices.get(&address.to_string())
+    }
+
+    /// The name from the config, if there is one, otherwise the one the device advertises.
+    pub fn device_name(&self, device: &BTDevice) -> String {
+        self.device(&device.address)
+            .and_then(|device| device.name.clone())
+            .unwrap_or_else(|| device.name.clone())
+    }
+
+    pub fn is_hidden(&self, address: &Address) -> bool {
+        self.device(address).is_some_and(|device| device.hidden)
+    }
+}
+
+pub fn config_path() -> Result<PathBuf> {
+    let config_dir = match std::env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => match std::env::var("HOME") {
+            Ok(home) => Path::new(&home).join(".config"),
+            Err(e) => bail!("Neither XDG_CONFIG_HOME nor HOME is set: {}", e),
+        },
+    };
+
+    Ok(config_dir.join(CONFIG_DIR).join(CONFIG_FILE))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Reads the config file, falling back to the defaults when there isn't one.
+pub fn load(path: &Path) -> Result<Config> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+        Err(e) => return Err(e).context(format!("Failed to read {}", path.display())),
+    };
+
+    Config::parse(&contents).context(format!("Invalid config in {}", path.display()))
+}
+
+/// A snapshot of the current config. Cheap, since a reload replaces the config rather than
+/// changing it.
+pub fn current() -> Arc<Config> {
+    CONFIG.read().unwrap().clone()
+}
+
+pub fn set(config: Config) {
+    log::set_max_level(config.log_level());
+    *CONFIG.write().unwrap() = Arc::new(config);
+}
+
+/// Reloads the config whenever the file changes. An invalid file is reported and the previous
+/// config is kept.
+pub async fn watch_config(path: PathBuf, app_tx: Sender<AppEvent>) {
+    // The file is checked and read on the blocking pool, so that a slow disk doesn't hold up the
+    // rest of the applet.
+    let mut last_modified = {
+        let path = path.clone();
+        tokio::task::spawn_blocking(move || modified(&path))
+            .await
+            .unwrap_or_default()
+    };
+    let mut interval = tokio::time::interval(Duration::from_millis(CONFIG_POLL_MS));
+
+    loop {
+        interval.tick().await;
+
+        let changed = {
+            let path = path.clone();
+            tokio::task::spawn_blocking(move || {
+                let new_modified = modified(&path);
+                (new_modified != last_modified).then(|| (new_modified, load(&path)))
+            })
+            .await
+        };
+
+        let Ok(Some((new_modified, result))) = changed else {
+            continue;
+        };
+
+        last_modified = new_modified;
+
+        match result {
+            Ok(config) => {
+                info!("Reloaded config from {}", path.display());
+                set(config);
+                let _ = app_tx.send(AppEvent::ConfigChanged).await;
+            }
+            Err(e) => error!("{:#}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_file_uses_defaults() {
+        let config = Config::parse("").unwrap();
+
+        assert_eq!(config.log_level(), LevelFilter::Info);
+        assert_eq!(config.bluetooth.session_retries, 16);
+        assert!(config.tray.show_available_devices);
+    }
+
+    #[test]
+    fn device_addresses_are_normalised() {
+        let config = Config::parse(
+            r#"
+            [devices."aa:bb:cc:dd:ee:ff"]
+            name = "Work headset"
+            "#,
+        )
+        .unwrap();
+
+        let address = Address::new([0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]);
+        let device = config.device(&address).unwrap();
+        assert_eq!(device.name.as_deref(), Some("Work headset"));
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        for contents in [
+            "log_level = \"loud\"",
+            "[bluetooth]\npower_poll_ms = 10",
+            "[bluetooth]\nsession_retries = 40",
+            "[bluetooth]\nreconnect_attempts = 0",
+            "[bluetooth]\ndiscoverable_timeout_ms = 500",
+            "[devices.headset]\nhidden = true",
+            "[tray]\nshow_everything = true",
+            "[notifications]\nbattery_thresholds = [0]",
+            "[tray.icons]\nconnected = \"icons/connected.png\"",
+        ] {
+            assert!(Config::parse(contents).is_err(), "{contents}");
+        }
+    }
+}
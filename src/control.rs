@@ -0,0 +1,243 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use bluer::Address;
+use log::error;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{
+    mpsc::{Sender, channel},
+    oneshot,
+};
+use zbus::{fdo, interface, object_server::SignalEmitter, proxy, zvariant::Type};
+
+use crate::{
+    APP_ID,
+    app::AppEvent,
+    bluetooth::{Action, BTDevice, BTState},
+};
+
+pub const CONTROL_PATH: &str = "/com/collinslagat/applets/BtNotSports";
+
+#[derive(Debug)]
+pub enum ControlEvent {
+    Update(BTState),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct DeviceInfo {
+    pub address: String,
+    pub name: String,
+    pub paired: bool,
+    pub connected: bool,
+    /// -1 when the device doesn't report its battery level.
+    pub battery_percentage: i16,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Type)]
+pub struct StateInfo {
+    pub adapter: String,
+    pub on: bool,
+    pub soft_blocked: bool,
+    pub hard_blocked: bool,
+    pub devices: Vec<DeviceInfo>,
+}
+
+impl From<&BTDevice> for DeviceInfo {
+    fn from(device: &BTDevice) -> Self {
+        Self {
+            address: device.address.to_string(),
+            name: device.name.clone(),
+            paired: device.is_paired,
+            connected: device.is_on(),
+            battery_percentage: device.battery_percentage.map_or(-1, i16::from),
+        }
+    }
+}
+
+impl From<&BTState> for StateInfo {
+    fn from(state: &BTState) -> Self {
+        let Some(adapter) = state.adapter() else {
+            return Self::default();
+        };
+
+        Self {
+            adapter: adapter.name.clone(),
+            on: adapter.on,
+            soft_blocked: adapter.soft_blocked,
+            hard_blocked: adapter.hard_blocked,
+            devices: adapter
+                .paired_devices
+                .iter()
+                .chain(&adapter.available_devices)
+                .map(DeviceInfo::from)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Control {
+    app_tx: Sender<AppEvent>,
+    /// Kept outside the interface, which stays locked while a method call waits for its action.
+    state: Arc<Mutex<BTState>>,
+}
+
+impl Control {
+    /// Sends the action and waits for it to be carried out.
+    async fn send_action(&self, action: Action) -> fdo::Result<()> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+
+        self.app_tx
+            .send(AppEvent::Call(action, reply_tx))
+            .await
+            .map_err(|e| fdo::Error::Failed(format!("Failed to send action: {}", e)))?;
+
+        match reply_rx.await {
+            Ok(result) => result.map_err(|failure| fdo::Error::Failed(failure.to_string())),
+            Err(_) => Err(fdo::Error::Failed(
+                "The action was dropped before it finished".to_string(),
+            )),
+        }
+    }
+
+    fn device(&self, address: &str) -> fdo::Result<BTDevice> {
+        let address = address
+            .parse::<Address>()
+            .map_err(|e| fdo::Error::InvalidArgs(format!("Invalid address {}: {}", address, e)))?;
+
+        self.state
+            .lock()
+            .unwrap()
+            .adapter()
+            .and_then(|adapter| {
+                adapter
+                    .paired_devices
+                    .iter()
+                    .chain(&adapter.available_devices)
+                    .find(|device| device.address == address)
+            })
+            .cloned()
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("Unknown device {}", address)))
+    }
+}
+
+#[interface(name = "com.collinslagat.applets.BtNotSports")]
+impl Control {
+    async fn toggle_bluetooth(&self) -> fdo::Result<()> {
+        self.send_action(Action::ToggleBluetooth).await
+    }
+
+    async fn connect(&self, address: &str) -> fdo::Result<()> {
+        let device = self.device(address)?;
+
+        if device.is_on() {
+            return Ok(());
+        }
+
+        self.send_action(Action::ToggleDevice(device)).await
+    }
+
+    async fn disconnect(&self, address: &str) -> fdo::Result<()> {
+        let device = self.device(address)?;
+
+        if !device.is_on() {
+            return Ok(());
+        }
+
+        self.send_action(Action::ToggleDevice(device)).await
+    }
+
+    async fn pair(&self, address: &str) -> fdo::Result<()> {
+        let device = self.device(address)?;
+
+        if device.is_paired {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "Device {} is already paired",
+                address
+            )));
+        }
+
+        self.send_action(Action::PairDevice(device)).await
+    }
+
+    /// An empty name goes back to the one the device advertises.
+    async fn rename(&self, address: &str, name: &str) -> fdo::Result<()> {
+        let device = self.device(address)?;
+
+        self.send_action(Action::RenameDevice(
+            device.address,
+            name.trim().to_string(),
+        ))
+        .await
+    }
+
+    async fn get_state(&self) -> StateInfo {
+        StateInfo::from(&*self.state.lock().unwrap())
+    }
+
+    #[zbus(signal)]
+    async fn state_changed(emitter: &SignalEmitter<'_>, state: StateInfo) -> zbus::Result<()>;
+}
+
+/// Client side of `Control`, used by the command line client to talk to a running applet.
+#[proxy(
+    interface = "com.collinslagat.applets.BtNotSports",
+    default_service = "com.collinslagat.applets.bt-notsports",
+    default_path = "/com/collinslagat/applets/BtNotSports"
+)]
+pub trait ControlClient {
+    fn toggle_bluetooth(&self) -> zbus::Result<()>;
+    fn connect(&self, address: &str) -> zbus::Result<()>;
+    fn disconnect(&self, address: &str) -> zbus::Result<()>;
+    fn rename(&self, address: &str, name: &str) -> zbus::Result<()>;
+    fn get_state(&self) -> zbus::Result<StateInfo>;
+    #[zbus(signal)]
+    fn state_changed(&self, state: StateInfo) -> zbus::Result<()>;
+}
+
+pub async fn init_control(app_tx: Sender<AppEvent>) -> Result<Sender<ControlEvent>> {
+    let shared_state = Arc::new(Mutex::new(BTState::default()));
+    let control = Control {
+        app_tx,
+        state: shared_state.clone(),
+    };
+
+    let connection = match zbus::connection::Builder::session()?
+        .name(APP_ID)?
+        .serve_at(CONTROL_PATH, control)?
+        .build()
+        .await
+    {
+        Ok(connection) => connection,
+        Err(e) => {
+            anyhow::bail!("Failed to serve control interface: {}", e);
+        }
+    };
+
+    let iface_ref = connection
+        .object_server()
+        .interface::<_, Control>(CONTROL_PATH)
+        .await?;
+
+    let (tx, mut rx) = channel::<ControlEvent>(32);
+
+    tokio::spawn(async move {
+        // Keeps the bus name and the object alive for as long as the app runs.
+        let _connection = connection;
+
+        while let Some(event) = rx.recv().await {
+            match event {
+                ControlEvent::Update(state) => {
+                    let info = StateInfo::from(&state);
+                    *shared_state.lock().unwrap() = state;
+
+                    if let Err(e) = Control::state_changed(iface_ref.signal_emitter(), info).await {
+                        error!("Control: Failed to emit StateChanged: {}", e);
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(tx)
+}
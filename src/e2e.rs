@@ -0,0 +1,672 @@
+//! Runs the applet against a private `dbus-daemon` serving a mock BlueZ and status notifier
+//! watcher, so that everything from a menu click down to the BlueZ method call is exercised.
+//!
+//! The applet finds its buses and directories through the environment, which can't safely be
+//! changed while other tests run. So the applet runs in a test process of its own instead.
+//!
+//! `dbus-daemon` has to be installed. Without it the test fails rather than pass without running.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use zbus::{
+    Connection, ObjectServer, Proxy, fdo, interface,
+    object_server::SignalEmitter,
+    zvariant::{OwnedObjectPath, OwnedValue, Value},
+};
+
+use crate::{
+    app::App,
+    bluetooth::init_bluetooth,
+    cli,
+    control::{ControlClientProxy, init_control},
+    notifications::init_notifications,
+    tray::init_tray,
+    wait_for_session_bus_and_status_notifier,
+};
+
+/// Not hci0, so that the rfkill state of the machine running the tests doesn't leak in.
+const ADAPTER_PATH: &str = "/org/bluez/hci42";
+const DEVICE_ADDRESS: &str = "00:11:22:33:44:55";
+const DEVICE_PATH: &str = "/org/bluez/hci42/dev_00_11_22_33_44_55";
+
+/// Set for the process running the applet, to the address of the private bus.
+const E2E_BUS: &str = "BT_NOTSPORTS_E2E_BUS";
+
+/// BlueZ method calls and property writes, in the order they were made.
+type Calls = Arc<Mutex<Vec<String>>>;
+
+fn record(calls: &Calls, call: impl Into<String>) {
+    calls.lock().unwrap().push(call.into());
+}
+
+/// A `dbus-daemon` of our own that is stopped, and its socket removed, when dropped.
+struct DBusDaemon {
+    child: Child,
+    dir: PathBuf,
+    address: String,
+}
+
+impl DBusDaemon {
+    fn start() -> Self {
+        let dir = std::env::temp_dir().join(format!("bt-notsports-e2e-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let config = dir.join("bus.conf");
+        std::fs::write(
+            &config,
+            format!(
+                r#"<!DOCTYPE busconfig PUBLIC "-//freedesktop//DTD D-Bus Bus Configuration 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/busconfig.dtd">
+<busconfig>
+  <type>session</type>
+  <listen>unix:path={}</listen>
+  <auth>EXTERNAL</auth>
+  <policy context="default">
+    <allow send_destination="*" eavesdrop="true"/>
+    <allow eavesdrop="true"/>
+    <allow own="*"/>
+  </policy>
+</busconfig>
+"#,
+                dir.join("bus").display()
+            ),
+        )
+        .unwrap();
+
+        let mut child = match Command::new("dbus-daemon")
+            .arg(format!("--config-file={}", config.display()))
+            .args(["--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => {
+                let _ = std::fs::remove_dir_all(&dir);
+                panic!("Failed to start dbus-daemon, which the end-to-end test needs: {e}");
+            }
+        };
+
+        // The address is printed once the daemon is listening.
+        let mut address = String::new();
+        BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+
+        Self {
+            child,
+            dir,
+            address: address.trim().to_string(),
+        }
+    }
+}
+
+impl Drop for DBusDaemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+struct AgentManager {
+    calls: Calls,
+}
+
+#[interface(name = "org.bluez.AgentManager1")]
+impl AgentManager {
+    fn register_agent(&self, _agent: OwnedObjectPath, capability: String) {
+        record(&self.calls, format!("RegisterAgent {capability}"));
+    }
+
+    fn request_default_agent(&self, _agent: OwnedObjectPath) {
+        record(&self.calls, "RequestDefaultAgent");
+    }
+
+    fn unregister_agent(&self, _agent: OwnedObjectPath) {
+        record(&self.calls, "UnregisterAgent");
+    }
+}
+
+struct Adapter {
+    calls: Calls,
+    powered: bool,
+    discovering: bool,
+    discoverable: bool,
+    discoverable_timeout: u32,
+}
+
+#[interface(name = "org.bluez.Adapter1")]
+impl Adapter {
+    async fn start_discovery(&mut self, #[zbus(signal_emitter)] emitter: SignalEmitter<'_>) {
+        record(&self.calls, "StartDiscovery");
+        self.discovering = true;
+        let _ = self.discovering_changed(&emitter).await;
+    }
+
+    async fn stop_discovery(&mut self, #[zbus(signal_emitter)] emitter: SignalEmitter<'_>) {
+        record(&self.calls, "StopDiscovery");
+        self.discovering = false;
+        let _ = self.discovering_changed(&emitter).await;
+    }
+
+    fn set_discovery_filter(&self, _filter: HashMap<String, OwnedValue>) {}
+
+    async fn remove_device(
+        &self,
+        #[zbus(object_server)] server: &ObjectServer,
+        device: OwnedObjectPath,
+    ) -> fdo::Result<()> {
+        record(&self.calls, format!("RemoveDevice {}", device.as_str()));
+        server.remove::<Battery, _>(&device).await?;
+        server.remove::<Device, _>(&device).await?;
+        Ok(())
+    }
+
+    #[zbus(property)]
+    fn address(&self) -> String {
+        "AA:BB:CC:DD:EE:FF".to_string()
+    }
+
+    #[zbus(property)]
+    fn address_type(&self) -> String {
+        "public".to_string()
+    }
+
+    #[zbus(property)]
+    fn name(&self) -> String {
+        "mock".to_string()
+    }
+
+    #[zbus(property)]
+    fn alias(&self) -> String {
+        "Mock adapter".to_string()
+    }
+
+    #[zbus(property)]
+    fn powered(&self) -> bool {
+        self.powered
+    }
+
+    #[zbus(property)]
+    fn set_powered(&mut self, powered: bool) {
+        record(&self.calls, format!("Powered {powered}"));
+        self.powered = powered;
+    }
+
+    #[zbus(property)]
+    fn discovering(&self) -> bool {
+        self.discovering
+    }
+
+    #[zbus(property)]
+    fn discoverable(&self) -> bool {
+        self.discoverable
+    }
+
+    #[zbus(property)]
+    fn set_discoverable(&mut self, discoverable: bool) {
+        record(&self.calls, format!("Discoverable {discoverable}"));
+        self.discoverable = discoverable;
+    }
+
+    #[zbus(property)]
+    fn discoverable_timeout(&self) -> u32 {
+        self.discoverable_timeout
+    }
+
+    #[zbus(property)]
+    fn set_discoverable_timeout(&mut self, timeout: u32) {
+        record(&self.calls, format!("DiscoverableTimeout {timeout}"));
+        self.discoverable_timeout = timeout;
+    }
+
+    #[zbus(property)]
+    fn pairable(&self) -> bool {
+        true
+    }
+
+    #[zbus(property, name = "UUIDs")]
+    fn uuids(&self) -> Vec<String> {
+        vec![]
+    }
+}
+
+struct Device {
+    calls: Calls,
+    alias: String,
+    blocked: bool,
+    connected: bool,
+    trusted: bool,
+}
+
+#[interface(name = "org.bluez.Device1")]
+impl Device {
+    async fn connect(&mut self, #[zbus(signal_emitter)] emitter: SignalEmitter<'_>) {
+        record(&self.calls, format!("Connect {DEVICE_ADDRESS}"));
+        self.connected = true;
+        let _ = self.connected_changed(&emitter).await;
+    }
+
+    async fn disconnect(&mut self, #[zbus(signal_emitter)] emitter: SignalEmitter<'_>) {
+        record(&self.calls, format!("Disconnect {DEVICE_ADDRESS}"));
+        self.connected = false;
+        let _ = self.connected_changed(&emitter).await;
+    }
+
+    fn pair(&self) {
+        record(&self.calls, format!("Pair {DEVICE_ADDRESS}"));
+    }
+
+    #[zbus(property)]
+    fn address(&self) -> String {
+        DEVICE_ADDRESS.to_string()
+    }
+
+    #[zbus(property)]
+    fn address_type(&self) -> String {
+        "public".to_string()
+    }
+
+    #[zbus(property)]
+    fn name(&self) -> String {
+        "Headset".to_string()
+    }
+
+    #[zbus(property)]
+    fn alias(&self) -> String {
+        self.alias.clone()
+    }
+
+    #[zbus(property)]
+    fn set_alias(&mut self, alias: String) {
+        record(&self.calls, format!("Alias {alias}"));
+        self.alias = alias;
+    }
+
+    #[zbus(property)]
+    fn blocked(&self) -> bool {
+        self.blocked
+    }
+
+    #[zbus(property)]
+    fn set_blocked(&mut self, blocked: bool) {
+        record(&self.calls, format!("Blocked {blocked}"));
+        self.blocked = blocked;
+    }
+
+    #[zbus(property)]
+    fn icon(&self) -> String {
+        "audio-headset".to_string()
+    }
+
+    #[zbus(property)]
+    fn paired(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn connected(&self) -> bool {
+        self.connected
+    }
+
+    #[zbus(property)]
+    fn trusted(&self) -> bool {
+        self.trusted
+    }
+
+    #[zbus(property)]
+    fn set_trusted(&mut self, trusted: bool) {
+        record(&self.calls, format!("Trusted {trusted}"));
+        self.trusted = trusted;
+    }
+
+    #[zbus(property)]
+    fn adapter(&self) -> OwnedObjectPath {
+        OwnedObjectPath::try_from(ADAPTER_PATH).unwrap()
+    }
+}
+
+struct Battery;
+
+#[interface(name = "org.bluez.Battery1")]
+impl Battery {
+    #[zbus(property)]
+    fn percentage(&self) -> u8 {
+        80
+    }
+}
+
+struct Watcher {
+    items: Arc<Mutex<Vec<String>>>,
+}
+
+#[interface(name = "org.kde.StatusNotifierWatcher")]
+impl Watcher {
+    fn register_status_notifier_item(&self, service: String) {
+        self.items.lock().unwrap().push(service);
+    }
+
+    fn register_status_notifier_host(&self, _service: String) {}
+
+    #[zbus(property)]
+    fn registered_status_notifier_items(&self) -> Vec<String> {
+        self.items.lock().unwrap().clone()
+    }
+
+    #[zbus(property)]
+    fn is_status_notifier_host_registered(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn protocol_version(&self) -> i32 {
+        0
+    }
+}
+
+/// One adapter that is on, with a paired headset that isn't connected yet.
+async fn serve_mocks(
+    address: &str,
+    calls: Calls,
+    items: Arc<Mutex<Vec<String>>>,
+) -> zbus::Result<Connection> {
+    zbus::connection::Builder::address(address)?
+        .name("org.bluez")?
+        .name("org.kde.StatusNotifierWatcher")?
+        .serve_at("/", fdo::ObjectManager)?
+        .serve_at(
+            "/org/bluez",
+            AgentManager {
+                calls: calls.clone(),
+            },
+        )?
+        .serve_at(
+            ADAPTER_PATH,
+            Adapter {
+                calls: calls.clone(),
+                powered: true,
+                discovering: false,
+                discoverable: false,
+                discoverable_timeout: 0,
+            },
+        )?
+        .serve_at(
+            DEVICE_PATH,
+            Device {
+                calls,
+                alias: "Headset".to_string(),
+                blocked: false,
+                connected: false,
+                trusted: true,
+            },
+        )?
+        .serve_at(DEVICE_PATH, Battery)?
+        .serve_at("/StatusNotifierWatcher", Watcher { items })?
+        .build()
+        .await
+}
+
+type RawLayout = (i32, HashMap<String, OwnedValue>, Vec<OwnedValue>);
+
+/// The parts of a dbusmenu item the tests look at.
+#[derive(Debug)]
+struct MenuItem {
+    id: i32,
+    label: String,
+    /// Only set for checkmarks.
+    checked: Option<bool>,
+    children: Vec<MenuItem>,
+}
+
+impl MenuItem {
+    fn parse((id, properties, children): RawLayout) -> Self {
+        let label = properties
+            .get("label")
+            .and_then(|label| String::try_from(label.clone()).ok())
+            .unwrap_or_default();
+        let checked = properties
+            .get("toggle-state")
+            .and_then(|state| i32::try_from(state).ok())
+            .map(|state| state == 1);
+
+        Self {
+            id,
+            label,
+            checked,
+            children: children
+                .into_iter()
+                .map(|child| MenuItem::parse(RawLayout::try_from(child).unwrap()))
+                .collect(),
+        }
+    }
+
+    fn find(&self, label: &str) -> Option<&MenuItem> {
+        self.find_where(&|item| item.label == label)
+    }
+
+    fn find_where(&self, f: &impl Fn(&MenuItem) -> bool) -> Option<&MenuItem> {
+        if f(self) {
+            return Some(self);
+        }
+
+        self.children.iter().find_map(|child| child.find_where(f))
+    }
+}
+
+/// The tray's menu, as a host would see it.
+struct Menu<'a> {
+    proxy: Proxy<'a>,
+}
+
+impl<'a> Menu<'a> {
+    async fn new(connection: &Connection, service: String) -> Self {
+        let proxy = Proxy::new(connection, service, "/MenuBar", "com.canonical.dbusmenu")
+            .await
+            .unwrap();
+
+        Self { proxy }
+    }
+
+    async fn layout(&self) -> MenuItem {
+        let (_revision, layout): (u32, RawLayout) = self
+            .proxy
+            .call("GetLayout", &(0, -1, Vec::<String>::new()))
+            .await
+            .unwrap();
+
+        MenuItem::parse(layout)
+    }
+
+    /// Waits for the menu to match, since it's updated after BlueZ's signals come in.
+    async fn wait_for(&self, f: impl Fn(&MenuItem) -> bool) -> MenuItem {
+        for _ in 0..100 {
+            let layout = self.layout().await;
+
+            if f(&layout) {
+                return layout;
+            }
+
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        panic!("Menu never matched: {:#?}", self.layout().await);
+    }
+
+    async fn click(&self, label: &str) {
+        let layout = self.wait_for(|layout| layout.find(label).is_some()).await;
+        let id = layout.find(label).unwrap().id;
+
+        self.proxy
+            .call::<_, _, ()>("Event", &(id, "clicked", Value::from(0), 0u32))
+            .await
+            .unwrap();
+    }
+}
+
+#[test]
+fn menu_clicks_reach_bluez() {
+    let daemon = DBusDaemon::start();
+
+    // The daemon stands in for both buses, and the config and favourites of whoever runs the
+    // tests are kept out of it.
+    let output = Command::new(std::env::current_exe().unwrap())
+        .args(["e2e::applet_on_private_bus", "--exact", "--ignored"])
+        .env(E2E_BUS, &daemon.address)
+        .env("DBUS_SESSION_BUS_ADDRESS", &daemon.address)
+        .env("DBUS_SYSTEM_BUS_ADDRESS", &daemon.address)
+        .env("XDG_CONFIG_HOME", &daemon.dir)
+        .env("XDG_STATE_HOME", &daemon.dir)
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    // A filter that matches nothing passes too.
+    assert!(
+        output.status.success() && stdout.contains("1 passed"),
+        "{}{}",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[tokio::test]
+#[ignore = "run by menu_clicks_reach_bluez, with the environment pointing at its private bus"]
+async fn applet_on_private_bus() {
+    let address = std::env::var(E2E_BUS).expect("only runs through menu_clicks_reach_bluez");
+
+    let calls = Calls::default();
+    let items = Arc::new(Mutex::new(Vec::new()));
+    let mocks = serve_mocks(&address, calls.clone(), items.clone())
+        .await
+        .unwrap();
+
+    wait_for_session_bus_and_status_notifier().await.unwrap();
+
+    let mut app = App::new();
+    let tray_tx = init_tray(app.get_sender()).await.unwrap();
+    let control_tx = init_control(app.get_sender()).await.unwrap();
+    let notification_tx = init_notifications().await.unwrap();
+    let bt_tx = init_bluetooth(app.get_sender()).await.unwrap();
+
+    let service = items.lock().unwrap().first().cloned().unwrap();
+    let menu = Menu::new(&mocks, service).await;
+
+    let clicks = async {
+        let layout = menu
+            .wait_for(|layout| layout.find("Headset (80%)").is_some())
+            .await;
+        assert_eq!(layout.find("Bluetooth").unwrap().checked, Some(true));
+        assert_eq!(layout.find("Trusted").unwrap().checked, Some(true));
+
+        menu.click("Connect").await;
+        menu.wait_for(|layout| layout.find("Headset (80%) - Connected").is_some())
+            .await;
+
+        // The way the command line client renames devices.
+        ControlClientProxy::new(&mocks)
+            .await
+            .unwrap()
+            .rename(DEVICE_ADDRESS, "Work headset")
+            .await
+            .unwrap();
+        menu.wait_for(|layout| layout.find("Work headset (80%) - Connected").is_some())
+            .await;
+
+        // The command line client only returns once the applet has carried its request out.
+        cli::run(
+            cli::Command::Disconnect(Some(DEVICE_ADDRESS.to_string())),
+            true,
+        )
+        .await
+        .unwrap();
+        assert!(
+            !ControlClientProxy::new(&mocks)
+                .await
+                .unwrap()
+                .get_state()
+                .await
+                .unwrap()
+                .devices[0]
+                .connected
+        );
+        cli::run(cli::Command::Connect("work headset".to_string()), true)
+            .await
+            .unwrap();
+        menu.wait_for(|layout| layout.find("Work headset (80%) - Connected").is_some())
+            .await;
+
+        // Paired devices stay in their own submenu while blocked.
+        menu.click("Block").await;
+        menu.click("Unblock").await;
+        menu.wait_for(|layout| layout.find("Block").is_some()).await;
+
+        menu.click("Forget").await;
+        let layout = menu
+            .wait_for(|layout| layout.find("Work headset (80%) - Connected").is_none())
+            .await;
+        assert!(layout.find("No devices found").is_some());
+
+        // Counts down from the default three minutes.
+        menu.click("Discoverable").await;
+        let layout = menu
+            .wait_for(|layout| {
+                layout
+                    .find_where(&|item| item.label.starts_with("Discoverable (2:"))
+                    .is_some()
+            })
+            .await;
+        let discoverable = layout
+            .find_where(&|item| item.label.starts_with("Discoverable ("))
+            .unwrap();
+        assert_eq!(discoverable.checked, Some(true));
+
+        // The tray ticks on its own, with nothing more heard from BlueZ.
+        let shown = discoverable.label.clone();
+        menu.wait_for(|layout| {
+            layout
+                .find_where(&|item| {
+                    item.label.starts_with("Discoverable (2:") && item.label != shown
+                })
+                .is_some()
+        })
+        .await;
+
+        menu.click("Bluetooth").await;
+        menu.wait_for(|layout| layout.find("Bluetooth").unwrap().checked == Some(false))
+            .await;
+    };
+
+    tokio::select! {
+        result = app.run(tray_tx, control_tx, notification_tx, bt_tx) => {
+            panic!("The app stopped early: {result:?}")
+        }
+        _ = clicks => {}
+    }
+
+    assert_eq!(
+        *calls.lock().unwrap(),
+        [
+            "RegisterAgent KeyboardDisplay".to_string(),
+            "RequestDefaultAgent".to_string(),
+            format!("Connect {DEVICE_ADDRESS}"),
+            "Alias Work headset".to_string(),
+            format!("Disconnect {DEVICE_ADDRESS}"),
+            format!("Connect {DEVICE_ADDRESS}"),
+            "Blocked true".to_string(),
+            "Blocked false".to_string(),
+            format!("RemoveDevice {DEVICE_PATH}"),
+            "DiscoverableTimeout 180".to_string(),
+            "Discoverable true".to_string(),
+            "Powered false".to_string(),
+        ]
+    );
+}
@@ -0,0 +1,155 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{Context, Result, bail};
+use bluer::Address;
+use log::error;
+
+const STATE_DIR: &str = "bt-notsports";
+const FAVOURITES_FILE: &str = "favourites.json";
+
+/// Devices that are connected automatically. Unlike the config file, this is written by the
+/// applet itself whenever a device is (un)marked from the menu.
+#[derive(Debug, Clone, Default)]
+pub struct Favourites {
+    path: Option<PathBuf>,
+    addresses: BTreeSet<Address>,
+}
+
+impl Favourites {
+    /// Loads the favourites from the state directory. Problems are logged and leave the list
+    /// empty rather than keeping bluetooth from starting.
+    pub fn load() -> Self {
+        let path = match favourites_path() {
+            Ok(path) => path,
+            Err(e) => {
+                error!("Can't remember favourite devices. {e:?}");
+                return Self::default();
+            }
+        };
+
+        let addresses = match read(&path) {
+            Ok(addresses) => addresses,
+            Err(e) => {
+                error!("{:#}", e);
+                BTreeSet::new()
+            }
+        };
+
+        Self {
+            path: Some(path),
+            addresses,
+        }
+    }
+
+    pub fn contains(&self, address: &Address) -> bool {
+        self.addresses.contains(address)
+    }
+
+    /// Returns whether anything changed. The file is left to `save`, so that it isn't written
+    /// while the favourites are locked.
+    pub fn set(&mut self, address: Address, favourite: bool) -> bool {
+        if favourite {
+            self.addresses.insert(address)
+        } else {
+            self.addresses.remove(&address)
+        }
+    }
+
+    /// Writes the favourites on the blocking pool.
+    pub async fn save(self) {
+        let Some(path) = self.path else {
+            return;
+        };
+
+        let result = tokio::task::spawn_blocking(move || write(&path, &self.addresses)).await;
+
+        if let Err(e) = result
+            .map_err(anyhow::Error::from)
+            .and_then(|result| result)
+        {
+            error!("{:#}", e);
+        }
+    }
+}
+
+fn favourites_path() -> Result<PathBuf> {
+    let state_dir = match std::env::var("XDG_STATE_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => match std::env::var("HOME") {
+            Ok(home) => Path::new(&home).join(".local").join("state"),
+            Err(e) => bail!("Neither XDG_STATE_HOME nor HOME is set: {}", e),
+        },
+    };
+
+    Ok(state_dir.join(STATE_DIR).join(FAVOURITES_FILE))
+}
+
+fn parse(contents: &str) -> Result<BTreeSet<Address>> {
+    serde_json::from_str::<Vec<String>>(contents)?
+        .iter()
+        .map(|address| {
+            Address::from_str(address)
+                .map_err(|_| anyhow::anyhow!("\"{}\" is not a bluetooth address", address))
+        })
+        .collect()
+}
+
+fn read(path: &Path) -> Result<BTreeSet<Address>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeSet::new()),
+        Err(e) => return Err(e).context(format!("Failed to read {}", path.display())),
+    };
+
+    parse(&contents).context(format!("Invalid favourites in {}", path.display()))
+}
+
+fn write(path: &Path, addresses: &BTreeSet<Address>) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).context(format!("Failed to create {}", dir.display()))?;
+    }
+
+    let addresses = addresses.iter().map(Address::to_string).collect::<Vec<_>>();
+
+    std::fs::write(path, serde_json::to_string_pretty(&addresses)?)
+        .context(format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn favourites_survive_a_round_trip() {
+        let path = std::env::temp_dir()
+            .join(format!("bt-notsports-{}", std::process::id()))
+            .join(FAVOURITES_FILE);
+        let address = Address::new([0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]);
+
+        let mut favourites = Favourites {
+            path: Some(path.clone()),
+            addresses: BTreeSet::new(),
+        };
+        assert!(favourites.set(address, true));
+        assert!(!favourites.set(address, true));
+        favourites.clone().save().await;
+
+        assert_eq!(read(&path).unwrap(), BTreeSet::from([address]));
+
+        assert!(favourites.set(address, false));
+        favourites.save().await;
+        assert!(read(&path).unwrap().is_empty());
+
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn invalid_addresses_are_rejected() {
+        assert!(parse(r#"["headset"]"#).is_err());
+        assert!(parse(r#"["aa:bb:cc:dd:ee:ff"]"#).is_ok());
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::HashSet;
+
+use bluer::{Uuid, UuidExt};
+
+/// What sort of device something is, as far as BlueZ can tell.
+///
+/// The variants are in the order devices are grouped in the menu.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DeviceKind {
+    Headset,
+    Headphones,
+    Speaker,
+    Keyboard,
+    Mouse,
+    Gamepad,
+    Tablet,
+    Phone,
+    Computer,
+    Watch,
+    Printer,
+    Camera,
+    #[default]
+    Other,
+}
+
+impl DeviceKind {
+    /// Works out the kind from the properties BlueZ reports, most specific first. Any of them can
+    /// be missing, especially on devices that haven't been paired.
+    pub fn detect(
+        icon: Option<&str>,
+        class: Option<u32>,
+        appearance: Option<u16>,
+        uuids: Option<&HashSet<Uuid>>,
+    ) -> Self {
+        [
+            icon.map(Self::from_icon),
+            class.map(Self::from_class),
+            appearance.map(Self::from_appearance),
+            uuids.map(Self::from_uuids),
+        ]
+        .into_iter()
+        .flatten()
+        .find(|kind| *kind != DeviceKind::Other)
+        .unwrap_or_default()
+    }
+
+    /// BlueZ derives the icon from the class or appearance, using freedesktop icon names.
+    pub fn from_icon(icon: &str) -> Self {
+        match icon {
+            "audio-headset" => DeviceKind::Headset,
+            "audio-headphones" => DeviceKind::Headphones,
+            "audio-card" => DeviceKind::Speaker,
+            "input-keyboard" => DeviceKind::Keyboard,
+            "input-mouse" => DeviceKind::Mouse,
+            "input-gaming" => DeviceKind::Gamepad,
+            "input-tablet" => DeviceKind::Tablet,
+            "phone" => DeviceKind::Phone,
+            "computer" => DeviceKind::Computer,
+            "printer" => DeviceKind::Printer,
+            "camera-photo" | "camera-video" => DeviceKind::Camera,
+            _ => DeviceKind::Other,
+        }
+    }
+
+    /// Classic bluetooth class of device, see the Assigned Numbers document.
+    pub fn from_class(class: u32) -> Self {
+        let major = (class >> 8) & 0x1f;
+        let minor = (class >> 2) & 0x3f;
+
+        match major {
+            0x01 => DeviceKind::Computer,
+            0x02 => DeviceKind::Phone,
+            0x04 => match minor {
+                0x01 | 0x02 => DeviceKind::Headset,
+                0x06 => DeviceKind::Headphones,
+                0x05 | 0x07 | 0x0a => DeviceKind::Speaker,
+                _ => DeviceKind::Other,
+            },
+            0x05 => match (minor >> 4, minor & 0x0f) {
+                (_, 0x01 | 0x02) => DeviceKind::Gamepad,
+                (_, 0x05) => DeviceKind::Tablet,
+                (0x01 | 0x03, _) => DeviceKind::Keyboard,
+                (0x02, _) => DeviceKind::Mouse,
+                _ => DeviceKind::Other,
+            },
+            0x06 if class & 0x80 != 0 => DeviceKind::Printer,
+            0x06 if class & 0x20 != 0 => DeviceKind::Camera,
+            0x07 if minor == 0x01 => DeviceKind::Watch,
+            _ => DeviceKind::Other,
+        }
+    }
+
+    /// Bluetooth LE GAP appearance, split into a category and a subcategory.
+    pub fn from_appearance(appearance: u16) -> Self {
+        let category = appearance >> 6;
+        let subcategory = appearance & 0x3f;
+
+        match (category, subcategory) {
+            (0x01, _) => DeviceKind::Phone,
+            (0x02, _) => DeviceKind::Computer,
+            (0x03, _) => DeviceKind::Watch,
+            (0x0f, 0x01) => DeviceKind::Keyboard,
+            (0x0f, 0x02) => DeviceKind::Mouse,
+            (0x0f, 0x03 | 0x04) => DeviceKind::Gamepad,
+            (0x0f, 0x05) => DeviceKind::Tablet,
+            (0x21, _) => DeviceKind::Speaker,
+            (0x25, 0x03) => DeviceKind::Headphones,
+            (0x25, _) => DeviceKind::Headset,
+            _ => DeviceKind::Other,
+        }
+    }
+
+    /// Falls back on the services a device offers.
+    pub fn from_uuids(uuids: &HashSet<Uuid>) -> Self {
+        let services = uuids
+            .iter()
+            .filter_map(UuidExt::as_u16)
+            .collect::<HashSet<_>>();
+
+        // Headset and Handsfree.
+        if services.contains(&0x1108) || services.contains(&0x111e) {
+            DeviceKind::Headset
+        // Audio sink.
+        } else if services.contains(&0x110b) {
+            DeviceKind::Speaker
+        } else {
+            DeviceKind::Other
+        }
+    }
+
+    /// A freedesktop icon name for the menu.
+    pub fn icon_name(&self) -> &'static str {
+        match self {
+            DeviceKind::Headset => "audio-headset",
+            DeviceKind::Headphones => "audio-headphones",
+            DeviceKind::Speaker => "audio-speakers",
+            DeviceKind::Keyboard => "input-keyboard",
+            DeviceKind::Mouse => "input-mouse",
+            DeviceKind::Gamepad => "input-gaming",
+            DeviceKind::Tablet => "input-tablet",
+            DeviceKind::Phone => "phone",
+            DeviceKind::Computer => "computer",
+            // There is no standard icon for watches.
+            DeviceKind::Watch => "preferences-desktop-peripherals",
+            DeviceKind::Printer => "printer",
+            DeviceKind::Camera => "camera-photo",
+            DeviceKind::Other => "bluetooth",
+        }
+    }
+
+    /// Heading for a group of devices of this kind.
+    pub fn label(&self) -> &'static str {
+        match self {
+            DeviceKind::Headset => "Headsets",
+            DeviceKind::Headphones => "Headphones",
+            DeviceKind::Speaker => "Speakers",
+            DeviceKind::Keyboard => "Keyboards",
+            DeviceKind::Mouse => "Mice",
+            DeviceKind::Gamepad => "Game controllers",
+            DeviceKind::Tablet => "Tablets",
+            DeviceKind::Phone => "Phones",
+            DeviceKind::Computer => "Computers",
+            DeviceKind::Watch => "Watches",
+            DeviceKind::Printer => "Printers",
+            DeviceKind::Camera => "Cameras",
+            DeviceKind::Other => "Other devices",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn class_of_device_is_decoded() {
+        // Sony WH-1000XM4.
+        assert_eq!(DeviceKind::from_class(0x240404), DeviceKind::Headset);
+        // Logitech MX Keys.
+        assert_eq!(DeviceKind::from_class(0x000540), DeviceKind::Keyboard);
+        // Logitech MX Master.
+        assert_eq!(DeviceKind::from_class(0x002580), DeviceKind::Mouse);
+        // DualShock 4.
+        assert_eq!(DeviceKind::from_class(0x002508), DeviceKind::Gamepad);
+        // Android phone.
+        assert_eq!(DeviceKind::from_class(0x5a020c), DeviceKind::Phone);
+        // Wearable, wristwatch.
+        assert_eq!(DeviceKind::from_class(0x000704), DeviceKind::Watch);
+        // Wearable, glasses.
+        assert_eq!(DeviceKind::from_class(0x000714), DeviceKind::Other);
+        assert_eq!(DeviceKind::from_class(0), DeviceKind::Other);
+    }
+
+    #[test]
+    fn most_specific_property_wins() {
+        let uuids = HashSet::from([Uuid::from_u16(0x110b)]);
+
+        assert_eq!(
+            DeviceKind::detect(Some("audio-headphones"), None, None, Some(&uuids)),
+            DeviceKind::Headphones
+        );
+        // An icon BlueZ doesn't map to anything useful doesn't hide the other properties.
+        assert_eq!(
+            DeviceKind::detect(Some("unknown"), None, Some(0x03c2), Some(&uuids)),
+            DeviceKind::Mouse
+        );
+        assert_eq!(
+            DeviceKind::detect(None, None, None, Some(&uuids)),
+            DeviceKind::Speaker
+        );
+        assert_eq!(
+            DeviceKind::detect(None, None, None, None),
+            DeviceKind::Other
+        );
+    }
+}
@@ -1,21 +1,41 @@
+mod agent;
 mod app;
+mod audio;
+mod backend;
 mod bluetooth;
+mod cli;
+mod config;
+mod control;
+#[cfg(test)]
+mod e2e;
+mod favourites;
+mod kind;
+mod notifications;
+mod prompt;
+mod rfkill;
 mod tray;
 
-use std::{fs::File, panic, path::Path};
+use std::{
+    fs::File,
+    panic,
+    path::{Path, PathBuf},
+};
 
 use anyhow::{Result, bail};
 use app::{App, AppEvent};
 use bluetooth::init_bluetooth;
+use cli::Command;
+use control::init_control;
 use fs2::FileExt;
 use futures::StreamExt;
 use log::{LevelFilter, error, info, warn};
+use notifications::init_notifications;
 use signal_hook::consts::{SIGINT, SIGTERM};
 use signal_hook_tokio::Signals;
 use simplelog::{ColorChoice, CombinedLogger, Config, TermLogger, TerminalMode, WriteLogger};
 use tokio::{fs, sync::mpsc::Sender};
 use tray::init_tray;
-use zbus::{Connection, Proxy};
+use zbus::{Connection, Proxy, fdo, names::BusName};
 
 pub const APP_ID: &str = "com.collinslagat.applets.bt-notsports";
 const LOCK_FILE: &str = "bt-notsports.lock";
@@ -23,23 +43,37 @@ const LOG_FILE: &str = "bt-notsports.log";
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+
+    if let Some(command) = Command::parse(&args)? {
+        setup_cli_logging();
+        return cli::run(command, is_daemon_running().await).await;
+    }
+
     if let Err(e) = setup_logging() {
         eprintln!("Failed to initialize logging: {}", e);
         std::process::exit(1);
     }
 
+    let config_path = config::config_path()?;
+    // Like a reload, an invalid file doesn't keep the applet from starting.
+    let config = config::load(&config_path).unwrap_or_else(|e| {
+        error!("{:#}. Using the default config.", e);
+        config::Config::default()
+    });
+
+    // Also applies the configured log level.
+    config::set(config);
+
+    if !args.is_empty() {
+        warn!("Ignoring unknown arguments: {}", args.join(" "));
+    }
+
     panic::set_hook(Box::new(|info| {
         error!("Unhandled panic: {}", info);
     }));
 
-    let runtime_dir = match std::env::var("XDG_RUNTIME_DIR") {
-        Ok(dir) => dir,
-        Err(e) => {
-            bail!("XDG_RUNTIME_DIR not set: {}", e);
-        }
-    };
-
-    let lock_file_path = Path::new(&runtime_dir).join(LOCK_FILE);
+    let lock_file_path = lock_file_path()?;
 
     let lock_file = match File::create(&lock_file_path) {
         Ok(file) => file,
@@ -64,8 +98,14 @@ async fn main() -> Result<()> {
 
     let signals_task = tokio::spawn(handle_signals(signals, app.get_sender()));
 
+    tokio::spawn(config::watch_config(config_path, app.get_sender()));
+
     let tray_tx = init_tray(app.get_sender()).await?;
 
+    let control_tx = init_control(app.get_sender()).await?;
+
+    let notification_tx = init_notifications().await?;
+
     let bt_tx = match init_bluetooth(app.get_sender()).await {
         Ok(tx) => tx,
         Err(e) => {
@@ -73,7 +113,7 @@ async fn main() -> Result<()> {
         }
     };
 
-    app.run(tray_tx, bt_tx).await?;
+    app.run(tray_tx, control_tx, notification_tx, bt_tx).await?;
 
     info!("Cleaning up");
 
@@ -87,6 +127,35 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+fn lock_file_path() -> Result<PathBuf> {
+    let runtime_dir = match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) => dir,
+        Err(e) => {
+            bail!("XDG_RUNTIME_DIR not set: {}", e);
+        }
+    };
+
+    Ok(Path::new(&runtime_dir).join(LOCK_FILE))
+}
+
+/// Checks whether the applet owns its bus name. The lock file is left alone, since probing it
+/// could make an applet that's just starting think another one is running.
+async fn is_daemon_running() -> bool {
+    let Ok(connection) = Connection::session().await else {
+        return false;
+    };
+
+    let Ok(dbus) = fdo::DBusProxy::new(&connection).await else {
+        return false;
+    };
+
+    let Ok(name) = BusName::try_from(APP_ID) else {
+        return false;
+    };
+
+    dbus.name_has_owner(name).await.unwrap_or_default()
+}
+
 async fn handle_signals(mut signals: Signals, tx: Sender<AppEvent>) {
     while let Some(signal) = signals.next().await {
         match signal {
@@ -116,10 +185,12 @@ fn setup_logging() -> Result<()> {
         }
     };
 
+    // Everything is let through here. The configured log level is applied with
+    // `log::set_max_level` so that it can change while the applet runs.
     if let Err(e) = CombinedLogger::init(vec![
-        WriteLogger::new(LevelFilter::Info, Config::default(), log_file),
+        WriteLogger::new(LevelFilter::Trace, Config::default(), log_file),
         TermLogger::new(
-            LevelFilter::Info,
+            LevelFilter::Trace,
             Config::default(),
             TerminalMode::Mixed,
             ColorChoice::Auto,
@@ -131,6 +202,16 @@ fn setup_logging() -> Result<()> {
     Ok(())
 }
 
+/// The command line client only logs to the terminal so that it doesn't clobber the applet's log.
+fn setup_cli_logging() {
+    let _ = TermLogger::init(
+        LevelFilter::Warn,
+        Config::default(),
+        TerminalMode::Stderr,
+        ColorChoice::Auto,
+    );
+}
+
 async fn wait_for_session_bus_and_status_notifier() -> Result<()> {
     // Give up after 20 attempts spanning 100ms each for a total of 2s
     let max_attempts = 20;
@@ -0,0 +1,388 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use bluer::Address;
+use log::error;
+use tokio::sync::mpsc::{Sender, channel};
+use zbus::{Connection, proxy, zvariant::Value};
+
+use crate::{
+    bluetooth::{BTAdapter, BTDevice, BTState},
+    config::{self, Config},
+};
+
+const APP_NAME: &str = "Bluetooth";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Notification {
+    Connected {
+        address: Address,
+        name: String,
+    },
+    Disconnected {
+        address: Address,
+        name: String,
+    },
+    Power {
+        adapter: String,
+        on: bool,
+    },
+    LowBattery {
+        address: Address,
+        name: String,
+        percentage: u8,
+    },
+}
+
+#[derive(Debug)]
+pub enum NotificationEvent {
+    Show(Notification),
+}
+
+impl Notification {
+    fn enabled(&self, config: &Config) -> bool {
+        let notifications = &config.notifications;
+
+        match self {
+            Notification::Connected { .. } => notifications.connected,
+            Notification::Disconnected { .. } => notifications.disconnected,
+            Notification::Power { .. } => notifications.power,
+            Notification::LowBattery { .. } => notifications.low_battery,
+        }
+    }
+
+    /// Identifies what the notification is about, for rate limiting and for replacing an earlier
+    /// notification about the same thing.
+    fn key(&self) -> String {
+        match self {
+            Notification::Connected { address, .. } => format!("connected {}", address),
+            Notification::Disconnected { address, .. } => format!("disconnected {}", address),
+            Notification::Power { adapter, .. } => format!("power {}", adapter),
+            Notification::LowBattery { address, .. } => format!("battery {}", address),
+        }
+    }
+
+    fn icon(&self) -> &'static str {
+        match self {
+            Notification::Connected { .. } => "bluetooth-active",
+            Notification::Disconnected { .. } => "bluetooth-disconnected",
+            Notification::Power { on: true, .. } => "bluetooth-active",
+            Notification::Power { on: false, .. } => "bluetooth-disabled",
+            Notification::LowBattery { .. } => "battery-caution",
+        }
+    }
+
+    fn summary(&self) -> String {
+        match self {
+            Notification::Connected { name, .. } => format!("{} connected", name),
+            Notification::Disconnected { name, .. } => format!("{} disconnected", name),
+            Notification::Power { on: true, .. } => "Bluetooth turned on".to_string(),
+            Notification::Power { on: false, .. } => "Bluetooth turned off".to_string(),
+            Notification::LowBattery { name, .. } => format!("{} battery low", name),
+        }
+    }
+
+    fn body(&self) -> String {
+        match self {
+            Notification::Power { adapter, .. } => adapter.clone(),
+            Notification::LowBattery { percentage, .. } => format!("{}% remaining", percentage),
+            _ => String::new(),
+        }
+    }
+
+    fn urgency(&self) -> u8 {
+        match self {
+            Notification::LowBattery { .. } => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// Works out what changed between two consecutive states that the user should be told about.
+pub fn diff(old: &BTState, new: &BTState, config: &Config) -> Vec<Notification> {
+    let mut notifications = vec![];
+
+    for adapter in &new.adapters {
+        // Adapters that just appeared, including every adapter in the very first state, have
+        // nothing to compare against.
+        let Some(old_adapter) = old.adapters.iter().find(|a| a.name == adapter.name) else {
+            continue;
+        };
+
+        if old_adapter.on != adapter.on {
+            notifications.push(Notification::Power {
+                adapter: adapter.alias.clone(),
+                on: adapter.on,
+            });
+        }
+
+        diff_devices(old_adapter, adapter, config, &mut notifications);
+    }
+
+    notifications
+}
+
+fn diff_devices(
+    old: &BTAdapter,
+    new: &BTAdapter,
+    config: &Config,
+    notifications: &mut Vec<Notification>,
+) {
+    let find = |devices: &[BTDevice], address: &Address| {
+        devices
+            .iter()
+            .find(|device| device.address == *address)
+            .cloned()
+    };
+
+    for device in &new.paired_devices {
+        if config.is_hidden(&device.address) {
+            continue;
+        }
+
+        let name = config.device_name(device);
+        let old_device = find(&old.paired_devices, &device.address)
+            .or_else(|| find(&old.available_devices, &device.address));
+
+        if device.is_on() && !old_device.as_ref().is_some_and(BTDevice::is_on) {
+            notifications.push(Notification::Connected {
+                address: device.address,
+                name: name.clone(),
+            });
+        }
+
+        if !device.is_on() && old_device.as_ref().is_some_and(BTDevice::is_on) {
+            notifications.push(Notification::Disconnected {
+                address: device.address,
+                name: name.clone(),
+            });
+        }
+
+        if let Some(old_percentage) = old_device.and_then(|d| d.battery_percentage)
+            && let Some(percentage) = device.battery_percentage
+            && config
+                .notifications
+                .battery_thresholds
+                .iter()
+                .any(|threshold| old_percentage > *threshold && percentage <= *threshold)
+        {
+            notifications.push(Notification::LowBattery {
+                address: device.address,
+                name,
+                percentage,
+            });
+        }
+    }
+
+    // Devices that were forgotten while connected. Turning the adapter off disconnects
+    // everything, which the power notification covers if it's shown.
+    if new.on {
+        for device in &old.paired_devices {
+            if device.is_on()
+                && find(&new.paired_devices, &device.address).is_none()
+                && !config.is_hidden(&device.address)
+            {
+                notifications.push(Notification::Disconnected {
+                    address: device.address,
+                    name: config.device_name(device),
+                });
+            }
+        }
+    } else if config.notifications.power {
+        notifications.retain(|n| !matches!(n, Notification::Disconnected { .. }));
+    }
+}
+
+#[proxy(
+    interface = "org.freedesktop.Notifications",
+    default_service = "org.freedesktop.Notifications",
+    default_path = "/org/freedesktop/Notifications"
+)]
+trait Notifications {
+    #[allow(clippy::too_many_arguments)]
+    fn notify(
+        &self,
+        app_name: &str,
+        replaces_id: u32,
+        app_icon: &str,
+        summary: &str,
+        body: &str,
+        actions: &[&str],
+        hints: HashMap<&str, Value<'_>>,
+        expire_timeout: i32,
+    ) -> zbus::Result<u32>;
+}
+
+pub async fn init_notifications() -> Result<Sender<NotificationEvent>> {
+    let connection = Connection::session().await?;
+    let proxy = NotificationsProxy::new(&connection).await?;
+
+    let (tx, mut rx) = channel::<NotificationEvent>(32);
+
+    tokio::spawn(async move {
+        // When each key was last notified, and the id of that notification.
+        let mut sent = HashMap::<String, (Instant, u32)>::new();
+
+        while let Some(event) = rx.recv().await {
+            match event {
+                NotificationEvent::Show(notification) => {
+                    let config = config::current();
+
+                    if !notification.enabled(&config) {
+                        continue;
+                    }
+
+                    let key = notification.key();
+                    let min_interval = Duration::from_millis(config.notifications.min_interval_ms);
+                    let previous = sent.get(&key).copied();
+
+                    if previous.is_some_and(|(at, _)| at.elapsed() < min_interval) {
+                        continue;
+                    }
+
+                    let hints = HashMap::from([("urgency", Value::from(notification.urgency()))]);
+
+                    match proxy
+                        .notify(
+                            APP_NAME,
+                            previous.map_or(0, |(_, id)| id),
+                            notification.icon(),
+                            &notification.summary(),
+                            &notification.body(),
+                            &[],
+                            hints,
+                            -1,
+                        )
+                        .await
+                    {
+                        Ok(id) => {
+                            sent.insert(key, (Instant::now(), id));
+                        }
+                        Err(e) => error!("Failed to show notification. {e:?}"),
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bluetooth::BTDeviceStatus, kind::DeviceKind};
+
+    fn device(connected: bool, battery_percentage: Option<u8>) -> BTDevice {
+        BTDevice {
+            name: "Headset".to_string(),
+            advertised_name: Some("Headset".to_string()),
+            address: Address::new([0, 0, 0, 0, 0, 1]),
+            status: if connected {
+                BTDeviceStatus::Connected
+            } else {
+                BTDeviceStatus::Paired
+            },
+            battery_percentage,
+            rssi: None,
+            tx_power: None,
+            last_seen: None,
+            is_paired: true,
+            is_trusted: false,
+            is_blocked: false,
+            kind: DeviceKind::Headset,
+            is_favourite: false,
+            reconnect: None,
+            audio: None,
+        }
+    }
+
+    fn state(on: bool, devices: Vec<BTDevice>) -> BTState {
+        BTState {
+            adapters: vec![BTAdapter {
+                name: "hci0".to_string(),
+                alias: "laptop".to_string(),
+                on,
+                paired_devices: devices,
+                ..Default::default()
+            }],
+            selected_adapter: Some("hci0".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn connection_changes_are_reported() {
+        let config = Config::default();
+        let disconnected = state(true, vec![device(false, None)]);
+        let connected = state(true, vec![device(true, None)]);
+
+        let notifications = diff(&disconnected, &connected, &config);
+        assert!(matches!(
+            notifications[..],
+            [Notification::Connected { .. }]
+        ));
+
+        let notifications = diff(&connected, &disconnected, &config);
+        assert!(matches!(
+            notifications[..],
+            [Notification::Disconnected { .. }]
+        ));
+
+        // Nothing to compare against on startup.
+        assert!(diff(&BTState::default(), &connected, &config).is_empty());
+    }
+
+    #[test]
+    fn powering_off_only_reports_the_adapter() {
+        let mut config = Config::default();
+        config.notifications.power = true;
+        let on = state(true, vec![device(true, None)]);
+        let off = state(false, vec![device(false, None)]);
+
+        let notifications = diff(&on, &off, &config);
+        assert_eq!(
+            notifications,
+            [Notification::Power {
+                adapter: "laptop".to_string(),
+                on: false
+            }]
+        );
+    }
+
+    #[test]
+    fn powering_off_reports_disconnects_without_power_notifications() {
+        let config = Config::default();
+        let on = state(true, vec![device(true, None)]);
+        let off = state(false, vec![device(false, None)]);
+
+        let notifications = diff(&on, &off, &config)
+            .into_iter()
+            .filter(|notification| notification.enabled(&config))
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            notifications[..],
+            [Notification::Disconnected { .. }]
+        ));
+    }
+
+    #[test]
+    fn low_battery_is_reported_once_per_threshold() {
+        let config = Config::default();
+        let battery = |percentage| state(true, vec![device(true, Some(percentage))]);
+
+        assert!(diff(&battery(30), &battery(21), &config).is_empty());
+        assert!(matches!(
+            diff(&battery(21), &battery(20), &config)[..],
+            [Notification::LowBattery { percentage: 20, .. }]
+        ));
+        assert!(diff(&battery(20), &battery(15), &config).is_empty());
+        assert!(matches!(
+            diff(&battery(15), &battery(9), &config)[..],
+            [Notification::LowBattery { percentage: 9, .. }]
+        ));
+    }
+}
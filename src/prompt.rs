@@ -0,0 +1,213 @@
+use std::{io::ErrorKind, process::Output, time::Duration};
+
+use bluer::Address;
+use log::{error, warn};
+use tokio::{process::Command, sync::mpsc::Sender};
+
+use crate::{
+    agent::{AgentPrompt, AgentReply, AgentRequest},
+    app::AppEvent,
+    bluetooth::Action,
+    config,
+};
+
+const PROMPT_TITLE: &str = "Bluetooth";
+
+#[derive(Debug, Clone, Copy)]
+enum Helper {
+    Zenity,
+    KDialog,
+}
+
+impl Helper {
+    fn command(&self, request: &AgentRequest, text: &str, timeout_secs: u64) -> Command {
+        let mut command = match self {
+            Helper::Zenity => {
+                let mut command = Command::new("zenity");
+
+                match request {
+                    AgentRequest::RequestPinCode | AgentRequest::RequestPasskey => {
+                        command.arg("--entry").arg(zenity_entry_text(text))
+                    }
+                    // Device names aren't Pango markup.
+                    AgentRequest::DisplayPinCode { .. } | AgentRequest::DisplayPasskey { .. } => {
+                        command
+                            .args(["--info", "--no-markup"])
+                            .arg(format!("--text={}", text))
+                    }
+                    AgentRequest::RequestConfirmation { .. }
+                    | AgentRequest::AuthorizeService { .. } => command
+                        .args(["--question", "--no-markup"])
+                        .arg(format!("--text={}", text)),
+                };
+
+                command
+                    .arg(format!("--title={}", PROMPT_TITLE))
+                    .arg(format!("--timeout={}", timeout_secs));
+                command
+            }
+            Helper::KDialog => {
+                let mut command = Command::new("kdialog");
+                command
+                    .arg("--title")
+                    .arg(PROMPT_TITLE)
+                    .arg(match request {
+                        AgentRequest::RequestPinCode | AgentRequest::RequestPasskey => "--inputbox",
+                        AgentRequest::DisplayPinCode { .. }
+                        | AgentRequest::DisplayPasskey { .. } => "--msgbox",
+                        AgentRequest::RequestConfirmation { .. }
+                        | AgentRequest::AuthorizeService { .. } => "--yesno",
+                    })
+                    .arg(text);
+                command
+            }
+        };
+
+        // The dialog must not outlive the request it belongs to.
+        command.kill_on_drop(true);
+        command
+    }
+
+    /// A text box filled in with `initial`.
+    fn entry_command(&self, text: &str, initial: &str, timeout_secs: u64) -> Command {
+        let mut command = match self {
+            Helper::Zenity => {
+                let mut command = Command::new("zenity");
+                command
+                    .arg("--entry")
+                    .arg(format!("--title={}", PROMPT_TITLE))
+                    .arg(zenity_entry_text(text))
+                    .arg(format!("--entry-text={}", initial))
+                    .arg(format!("--timeout={}", timeout_secs));
+                command
+            }
+            Helper::KDialog => {
+                let mut command = Command::new("kdialog");
+                command
+                    .arg("--title")
+                    .arg(PROMPT_TITLE)
+                    .arg("--inputbox")
+                    .arg(text)
+                    .arg(initial);
+                command
+            }
+        };
+
+        // Killed once the prompt times out, like the agent's dialogs.
+        command.kill_on_drop(true);
+        command
+    }
+}
+
+/// The `--text` of a zenity text box. It's not markup like the other dialogs, and doesn't take
+/// `--no-markup`, but an underscore marks a keyboard shortcut and a backslash starts an escape.
+fn zenity_entry_text(text: &str) -> String {
+    format!("--text={}", text.replace('\\', "\\\\").replace('_', "__"))
+}
+
+async fn run_helper(command: impl Fn(Helper) -> Command, text: &str) -> Option<Output> {
+    for helper in [Helper::Zenity, Helper::KDialog] {
+        match command(helper).output().await {
+            Ok(output) => return Some(output),
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => {
+                error!("Failed to run {:?} prompt helper. {e:?}", helper);
+                return None;
+            }
+        }
+    }
+
+    warn!("Neither zenity nor kdialog is installed. Can't show prompt: {text}");
+    None
+}
+
+pub async fn show_prompt(prompt: AgentPrompt) {
+    let AgentPrompt {
+        device_name,
+        request,
+        timeout,
+        reply_tx,
+        cancel,
+    } = prompt;
+
+    let text = request.message(&device_name);
+
+    let cancelled = async {
+        match cancel {
+            Some(cancel) => {
+                let _ = cancel.await;
+            }
+            None => futures::future::pending::<()>().await,
+        }
+    };
+
+    let output = tokio::select! {
+        output = tokio::time::timeout(
+            timeout,
+            run_helper(|helper| helper.command(&request, &text, timeout.as_secs()), &text),
+        ) => {
+            output.ok().flatten()
+        }
+        _ = cancelled => None,
+    };
+
+    let Some(reply_tx) = reply_tx else {
+        return;
+    };
+
+    let reply = match output {
+        Some(output) if output.status.success() => match request {
+            AgentRequest::RequestPinCode | AgentRequest::RequestPasskey => {
+                AgentReply::PinCode(String::from_utf8_lossy(&output.stdout).trim().to_string())
+            }
+            _ => AgentReply::Accept,
+        },
+        _ => AgentReply::Reject,
+    };
+
+    let _ = reply_tx.send(reply);
+}
+
+/// Asks for a new name for a device and renames it, unless the prompt is cancelled or the name
+/// is left as it was.
+pub async fn show_rename_prompt(address: Address, name: String, app_tx: Sender<AppEvent>) {
+    let text = format!("New name for {}:", name);
+    let timeout = Duration::from_millis(config::current().bluetooth.agent_prompt_timeout_ms);
+
+    let Ok(Some(output)) = tokio::time::timeout(
+        timeout,
+        run_helper(
+            |helper| helper.entry_command(&text, &name, timeout.as_secs()),
+            &text,
+        ),
+    )
+    .await
+    else {
+        return;
+    };
+
+    if !output.status.success() {
+        return;
+    }
+
+    let new_name = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    if new_name != name {
+        let _ = app_tx
+            .send(AppEvent::Request(Action::RenameDevice(address, new_name)))
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zenity_entry_text_is_shown_as_is() {
+        assert_eq!(
+            zenity_entry_text(r"New name for <A&B> my_phone\n:"),
+            r"--text=New name for <A&B> my__phone\\n:"
+        );
+    }
+}
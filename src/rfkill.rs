@@ -0,0 +1,415 @@
+//! Reads and changes radio kill switches through `/dev/rfkill`, see
+//! https://docs.kernel.org/driver-api/rfkill.html.
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, ErrorKind, Read, Write},
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    path::Path,
+};
+
+use log::error;
+use tokio::sync::mpsc::Sender;
+
+const RFKILL_DEVICE: &str = "/dev/rfkill";
+const RFKILL_SYSFS: &str = "/sys/class/rfkill";
+
+/// How often the thread reading `/dev/rfkill` checks whether anyone still listens.
+const WATCH_POLL_MS: i32 = 1_000;
+
+/// Size of the original `struct rfkill_event`. Newer kernels append more fields, but only send
+/// them to readers that ask for them with a bigger buffer.
+pub const EVENT_SIZE: usize = 8;
+
+const OP_ADD: u8 = 0;
+const OP_DEL: u8 = 1;
+const OP_CHANGE: u8 = 2;
+const OP_CHANGE_ALL: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RfkillType {
+    All,
+    Wlan,
+    Bluetooth,
+    Other(u8),
+}
+
+impl From<u8> for RfkillType {
+    fn from(kind: u8) -> Self {
+        match kind {
+            0 => RfkillType::All,
+            1 => RfkillType::Wlan,
+            2 => RfkillType::Bluetooth,
+            kind => RfkillType::Other(kind),
+        }
+    }
+}
+
+impl From<RfkillType> for u8 {
+    fn from(kind: RfkillType) -> Self {
+        match kind {
+            RfkillType::All => 0,
+            RfkillType::Wlan => 1,
+            RfkillType::Bluetooth => 2,
+            RfkillType::Other(kind) => kind,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RfkillOp {
+    Add,
+    Del,
+    Change,
+    ChangeAll,
+}
+
+/// `struct rfkill_event` from `linux/rfkill.h`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RfkillEvent {
+    pub idx: u32,
+    pub kind: RfkillType,
+    pub op: RfkillOp,
+    pub soft: bool,
+    pub hard: bool,
+}
+
+impl RfkillEvent {
+    pub fn parse(bytes: &[u8; EVENT_SIZE]) -> Option<Self> {
+        let op = match bytes[5] {
+            OP_ADD => RfkillOp::Add,
+            OP_DEL => RfkillOp::Del,
+            OP_CHANGE => RfkillOp::Change,
+            OP_CHANGE_ALL => RfkillOp::ChangeAll,
+            _ => return None,
+        };
+
+        Some(Self {
+            idx: u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            kind: RfkillType::from(bytes[4]),
+            op,
+            soft: bytes[6] != 0,
+            hard: bytes[7] != 0,
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; EVENT_SIZE] {
+        let idx = self.idx.to_ne_bytes();
+        let op = match self.op {
+            RfkillOp::Add => OP_ADD,
+            RfkillOp::Del => OP_DEL,
+            RfkillOp::Change => OP_CHANGE,
+            RfkillOp::ChangeAll => OP_CHANGE_ALL,
+        };
+
+        [
+            idx[0],
+            idx[1],
+            idx[2],
+            idx[3],
+            self.kind.into(),
+            op,
+            self.soft.into(),
+            self.hard.into(),
+        ]
+    }
+}
+
+/// Splits what was read from `/dev/rfkill` into events. Unknown operations are skipped.
+pub fn parse_events(bytes: &[u8]) -> Vec<RfkillEvent> {
+    bytes
+        .chunks_exact(EVENT_SIZE)
+        .filter_map(|chunk| RfkillEvent::parse(chunk.try_into().ok()?))
+        .collect()
+}
+
+/// A radio as last reported by the kernel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RfkillDevice {
+    pub idx: u32,
+    pub kind: RfkillType,
+    /// For bluetooth adapters this is the adapter name, e.g. `hci0`.
+    pub name: String,
+    pub soft: bool,
+    pub hard: bool,
+}
+
+/// Every radio the kernel knows about, kept up to date from rfkill events.
+#[derive(Debug, Clone, Default)]
+pub struct Rfkill {
+    devices: HashMap<u32, RfkillDevice>,
+}
+
+impl Rfkill {
+    /// Reads the current state of every radio without waiting for changes.
+    pub fn load() -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(RFKILL_DEVICE)?;
+
+        let mut rfkill = Self::default();
+        let mut buffer = [0; EVENT_SIZE];
+
+        // Opening the device queues an `Add` event for every radio.
+        loop {
+            match file.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => {
+                    for event in parse_events(&buffer[..read]) {
+                        rfkill.apply(&event, device_name);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(rfkill)
+    }
+
+    /// Applies an event. Returns `false` when nothing changed. `name` looks up the name of a
+    /// radio that's being added.
+    pub fn apply(&mut self, event: &RfkillEvent, name: impl Fn(u32) -> String) -> bool {
+        match event.op {
+            RfkillOp::Add | RfkillOp::Change => {
+                let device = RfkillDevice {
+                    idx: event.idx,
+                    kind: event.kind,
+                    name: match self.devices.get(&event.idx) {
+                        Some(device) => device.name.clone(),
+                        None => name(event.idx),
+                    },
+                    soft: event.soft,
+                    hard: event.hard,
+                };
+
+                self.devices.insert(event.idx, device.clone()) != Some(device)
+            }
+            RfkillOp::Del => self.devices.remove(&event.idx).is_some(),
+            // Only ever written by userspace, the kernel follows up with a `Change` per radio.
+            RfkillOp::ChangeAll => false,
+        }
+    }
+
+    pub fn device(&self, name: &str) -> Option<&RfkillDevice> {
+        self.devices.values().find(|device| device.name == name)
+    }
+
+    /// Whether every radio is soft blocked, which is what airplane mode amounts to.
+    pub fn all_soft_blocked(&self) -> bool {
+        !self.devices.is_empty() && self.devices.values().all(|device| device.soft)
+    }
+}
+
+/// The name sysfs gives the radio with the given index.
+fn device_name(idx: u32) -> String {
+    let path = Path::new(RFKILL_SYSFS)
+        .join(format!("rfkill{}", idx))
+        .join("name");
+
+    std::fs::read_to_string(path)
+        .map(|name| name.trim().to_string())
+        .unwrap_or_default()
+}
+
+fn write_event(event: RfkillEvent) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(RFKILL_DEVICE)?;
+    file.write_all(&event.to_bytes())
+}
+
+/// Soft blocks or unblocks the radio named `name`. Unlike powering the adapter off in BlueZ, this
+/// persists across reboots.
+pub fn set_soft_block(name: &str, blocked: bool) -> io::Result<()> {
+    let rfkill = Rfkill::load()?;
+
+    let Some(device) = rfkill.device(name) else {
+        return Err(io::Error::new(
+            ErrorKind::NotFound,
+            format!("No rfkill device named {}", name),
+        ));
+    };
+
+    write_event(RfkillEvent {
+        idx: device.idx,
+        kind: device.kind,
+        op: RfkillOp::Change,
+        soft: blocked,
+        hard: false,
+    })
+}
+
+/// Soft blocks or unblocks every radio, bluetooth and otherwise.
+pub fn set_all_soft_blocked(blocked: bool) -> io::Result<()> {
+    write_event(RfkillEvent {
+        idx: 0,
+        kind: RfkillType::All,
+        op: RfkillOp::ChangeAll,
+        soft: blocked,
+        hard: false,
+    })
+}
+
+/// Waits up to `WATCH_POLL_MS` for the file to become readable.
+fn wait_readable(file: &File) -> io::Result<bool> {
+    let mut fd = libc::pollfd {
+        fd: file.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+
+    // SAFETY: `fd` is a single valid pollfd that outlives the call, and the file stays open.
+    match unsafe { libc::poll(&mut fd, 1, WATCH_POLL_MS) } {
+        -1 => Err(io::Error::last_os_error()),
+        ready => Ok(ready > 0),
+    }
+}
+
+/// Sends every update to the rfkill state. Reading `/dev/rfkill` blocks, so this runs on a thread
+/// of its own until `tx` is closed.
+pub fn watch(tx: Sender<Rfkill>) -> io::Result<()> {
+    let mut file = File::open(RFKILL_DEVICE)?;
+
+    std::thread::spawn(move || {
+        let mut rfkill = Rfkill::default();
+        let mut buffer = [0; EVENT_SIZE];
+
+        loop {
+            // Waiting with a timeout rather than in `read`, so that the thread and the file don't
+            // outlive a receiver that's gone while no events come in.
+            if tx.is_closed() {
+                return;
+            }
+
+            match wait_readable(&file) {
+                Ok(true) => (),
+                Ok(false) => continue,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Failed to wait for {}. {e:?}", RFKILL_DEVICE);
+                    return;
+                }
+            }
+
+            match file.read(&mut buffer) {
+                Ok(0) => return,
+                Ok(read) => {
+                    let mut changed = false;
+
+                    for event in parse_events(&buffer[..read]) {
+                        changed |= rfkill.apply(&event, device_name);
+                    }
+
+                    if changed && tx.blocking_send(rfkill.clone()).is_err() {
+                        return;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Failed to read from {}. {e:?}", RFKILL_DEVICE);
+                    return;
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(idx: u32, kind: u8, op: u8, soft: u8, hard: u8) -> Vec<u8> {
+        let mut bytes = idx.to_ne_bytes().to_vec();
+        bytes.extend([kind, op, soft, hard]);
+        bytes
+    }
+
+    fn name(idx: u32) -> String {
+        match idx {
+            0 => "hci0".to_string(),
+            _ => "phy0".to_string(),
+        }
+    }
+
+    #[test]
+    fn recorded_events_are_parsed() {
+        // Opening /dev/rfkill on a laptop with a bluetooth adapter and a wifi card, followed by
+        // flipping the hardware switch on and off.
+        let bytes = [
+            event(0, 2, OP_ADD, 0, 0),
+            event(1, 1, OP_ADD, 0, 0),
+            event(0, 2, OP_CHANGE, 0, 1),
+            event(1, 1, OP_CHANGE, 0, 1),
+            event(0, 2, OP_CHANGE, 0, 0),
+            event(1, 1, OP_CHANGE, 0, 0),
+        ]
+        .concat();
+
+        let events = parse_events(&bytes);
+
+        assert_eq!(events.len(), 6);
+        assert_eq!(
+            events[0],
+            RfkillEvent {
+                idx: 0,
+                kind: RfkillType::Bluetooth,
+                op: RfkillOp::Add,
+                soft: false,
+                hard: false,
+            }
+        );
+        assert!(events[2].hard && !events[2].soft);
+        assert_eq!(events[3].kind, RfkillType::Wlan);
+    }
+
+    #[test]
+    fn events_round_trip() {
+        let event = RfkillEvent {
+            idx: 7,
+            kind: RfkillType::Bluetooth,
+            op: RfkillOp::Change,
+            soft: true,
+            hard: false,
+        };
+
+        assert_eq!(RfkillEvent::parse(&event.to_bytes()), Some(event));
+    }
+
+    #[test]
+    fn state_follows_events() {
+        let bytes = [
+            event(0, 2, OP_ADD, 0, 0),
+            event(1, 1, OP_ADD, 1, 0),
+            // A repeated change doesn't count as one.
+            event(1, 1, OP_CHANGE, 1, 0),
+            event(0, 2, OP_CHANGE, 1, 0),
+            event(1, 1, OP_DEL, 0, 0),
+            // Truncated and unknown events are dropped.
+            event(0, 2, 9, 0, 0),
+            vec![0, 0],
+        ]
+        .concat();
+
+        let mut rfkill = Rfkill::default();
+        let changes = parse_events(&bytes)
+            .iter()
+            .map(|event| rfkill.apply(event, name))
+            .collect::<Vec<_>>();
+
+        assert_eq!(changes, [true, true, false, true, true]);
+
+        let adapter = rfkill.device("hci0").unwrap();
+        assert!(adapter.soft && !adapter.hard);
+        assert_eq!(rfkill.devices.len(), 1);
+
+        // The wifi card comes back and airplane mode is turned on, as in `rfkill block all`.
+        rfkill.apply(&parse_events(&event(1, 1, OP_ADD, 0, 0))[0], name);
+        assert!(!rfkill.all_soft_blocked());
+
+        rfkill.apply(&parse_events(&event(1, 1, OP_CHANGE, 1, 0))[0], name);
+        assert!(rfkill.all_soft_blocked());
+    }
+}
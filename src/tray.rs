@@ -1,29 +1,106 @@
-use std::sync::LazyLock;
+use std::{collections::HashMap, path::Path, sync::LazyLock, time::Duration};
 
 use anyhow::Result;
 use image::GenericImageView;
 use ksni::{
     MenuItem, TrayMethods,
-    menu::{CheckmarkItem, StandardItem, SubMenu},
+    menu::{CheckmarkItem, RadioGroup, RadioItem, StandardItem, SubMenu},
 };
 use log::error;
-use tokio::sync::mpsc::{Sender, channel};
+use tokio::{
+    sync::mpsc::{Sender, channel},
+    time::Instant,
+};
 
 use crate::{
     APP_ID,
     app::AppEvent,
-    bluetooth::{Action, BTState},
+    bluetooth::{Action, BTFailure, BTState, Reconnect},
+    config::{self, TrayIconsConfig},
+    kind::DeviceKind,
 };
 
+/// How long a failed action is shown at the top of the menu.
+const FAILURE_DISPLAY_SECS: u64 = 10;
+
 #[derive(Debug)]
 pub enum TrayEvent {
     Update(BTState),
+    Failure(BTFailure),
+    /// Clears the failure with the given id, unless a newer one has replaced it.
+    ClearFailure(u64),
+}
+
+/// What the tray icon shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum IconState {
+    Off,
+    On,
+    Connected,
+    Scanning,
+    /// By a hardware switch or airplane mode. Turning bluetooth off from the menu soft blocks the
+    /// adapter too, but that's just off.
+    Blocked,
+}
+
+impl IconState {
+    const ALL: [IconState; 5] = [
+        IconState::Off,
+        IconState::On,
+        IconState::Connected,
+        IconState::Scanning,
+        IconState::Blocked,
+    ];
+
+    fn from_state(state: &BTState) -> Self {
+        let Some(adapter) = state.adapter() else {
+            return IconState::Off;
+        };
+
+        if adapter.hard_blocked || (adapter.soft_blocked && state.airplane_mode) {
+            IconState::Blocked
+        } else if !adapter.on {
+            IconState::Off
+        } else if adapter.scanning {
+            IconState::Scanning
+        } else if adapter.paired_devices.iter().any(|device| device.is_on()) {
+            IconState::Connected
+        } else {
+            IconState::On
+        }
+    }
+
+    /// Freedesktop icon names, so that the icon follows the user's theme. Hosts whose theme lacks
+    /// one show the pixmap instead.
+    fn icon_name(self) -> &'static str {
+        match self {
+            IconState::Off => "bluetooth-disabled",
+            IconState::On => "bluetooth-active",
+            IconState::Connected => "bluetooth-paired",
+            IconState::Scanning => "bluetooth-acquiring",
+            IconState::Blocked => "bluetooth-hardware-disabled",
+        }
+    }
+
+    fn custom_path(self, icons: &TrayIconsConfig) -> Option<&Path> {
+        match self {
+            IconState::Off => icons.off.as_deref(),
+            IconState::On => icons.on.as_deref(),
+            IconState::Connected => icons.connected.as_deref(),
+            IconState::Scanning => icons.scanning.as_deref(),
+            IconState::Blocked => icons.blocked.as_deref(),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Tray {
     app_tx: Sender<AppEvent>,
     state: BTState,
+    failure: Option<(u64, BTFailure)>,
+    /// The configured icons, and the images loaded from them.
+    icon_paths: TrayIconsConfig,
+    custom_icons: HashMap<IconState, ksni::Icon>,
 }
 
 impl Tray {
@@ -31,20 +108,35 @@ impl Tray {
         Tray {
             app_tx,
             state: BTState::default(),
+            failure: None,
+            icon_paths: TrayIconsConfig::default(),
+            custom_icons: HashMap::new(),
         }
     }
 
     pub fn update(&mut self, state: BTState) {
         self.state = state;
+
+        // Updates also follow config changes, so this is where changed icons are picked up.
+        let icon_paths = config::current().tray.icons.clone();
+
+        if icon_paths != self.icon_paths {
+            self.custom_icons = load_custom_icons(&icon_paths);
+            self.icon_paths = icon_paths;
+        }
     }
 
     fn send_action(&self, action: Action) -> Result<()> {
+        self.send_event(AppEvent::Request(action))
+    }
+
+    fn send_event(&self, event: AppEvent) -> Result<()> {
         let handle = tokio::runtime::Handle::current();
 
         let tx = self.app_tx.clone();
         handle.spawn(async move {
-            if let Err(e) = tx.send(AppEvent::Request(action)).await {
-                error!("Tray: Failed to send action: {}", e);
+            if let Err(e) = tx.send(event).await {
+                error!("Tray: Failed to send event: {}", e);
             }
         });
         Ok(())
@@ -56,30 +148,45 @@ impl ksni::Tray for Tray {
         APP_ID.to_string()
     }
 
-    fn icon_pixmap(&self) -> Vec<ksni::Icon> {
-        let mut icons = Vec::with_capacity(1);
+    fn icon_name(&self) -> String {
+        let state = IconState::from_state(&self.state);
 
+        // Hosts prefer the icon name over the pixmap, so leave it out for custom icons.
+        if self.custom_icons.contains_key(&state) {
+            String::new()
+        } else {
+            state.icon_name().to_string()
+        }
+    }
+
+    fn icon_pixmap(&self) -> Vec<ksni::Icon> {
         static ON_ICON: LazyLock<ksni::Icon> =
             LazyLock::new(|| get_icon_from_image_bytes(include_bytes!("../assets/on.png")));
 
         static OFF_ICON: LazyLock<ksni::Icon> =
             LazyLock::new(|| get_icon_from_image_bytes(include_bytes!("../assets/off.png")));
 
-        if self.state.on {
-            icons.push(ON_ICON.clone());
-        } else {
-            icons.push(OFF_ICON.clone());
-        }
+        let state = IconState::from_state(&self.state);
 
-        icons
+        // For hosts that can't find the icon name in the theme.
+        let icon = match self.custom_icons.get(&state) {
+            Some(icon) => icon.clone(),
+            None if matches!(state, IconState::Off | IconState::Blocked) => OFF_ICON.clone(),
+            None => ON_ICON.clone(),
+        };
+
+        vec![icon]
     }
 
     fn title(&self) -> String {
+        let config = config::current();
         let connected_devices = self
             .state
-            .paired_devices
+            .adapters
             .iter()
+            .flat_map(|adapter| &adapter.paired_devices)
             .filter(|device| device.is_on())
+            .map(|device| config.device_name(device))
             .collect::<Vec<_>>();
 
         if connected_devices.is_empty() {
@@ -88,9 +195,7 @@ impl ksni::Tray for Tray {
 
         let connected_devices_string: String = connected_devices
             .iter()
-            .fold("".to_string(), |acc, item| {
-                format!("{}{}, ", acc, item.name)
-            })
+            .fold("".to_string(), |acc, item| format!("{}{}, ", acc, item))
             .trim_end_matches(", ")
             .to_string();
 
@@ -99,11 +204,46 @@ impl ksni::Tray for Tray {
 
     fn menu(&self) -> Vec<MenuItem<Self>> {
         let mut menu = vec![];
+        let config = config::current();
+        let stale_after = Duration::from_millis(config.bluetooth.device_stale_ms);
+
+        if let Some((_, failure)) = &self.failure {
+            menu.push(
+                StandardItem {
+                    label: failure.to_string(),
+                    icon_name: "dialog-warning".to_string(),
+                    enabled: false,
+                    ..Default::default()
+                }
+                .into(),
+            );
+            menu.push(MenuItem::Separator);
+        }
+
+        let mut adapter = self.state.adapter().cloned().unwrap_or_default();
+
+        adapter
+            .paired_devices
+            .retain(|device| !config.is_hidden(&device.address));
+        adapter
+            .available_devices
+            .retain(|device| !config.is_hidden(&device.address));
+        adapter
+            .blocked_devices
+            .retain(|device| !config.is_hidden(&device.address));
+
+        if config.tray.hide_unknown_devices {
+            adapter
+                .available_devices
+                .retain(|device| device.kind != DeviceKind::Other);
+        }
 
         menu.push(
             CheckmarkItem {
                 label: "Bluetooth".to_string(),
-                checked: self.state.on,
+                checked: adapter.on,
+                // Toggling would only fail, the switch has to be flipped first.
+                enabled: !adapter.hard_blocked,
                 activate: Box::new(|this: &mut Self| {
                     this.send_action(Action::ToggleBluetooth).unwrap();
                 }),
@@ -112,10 +252,102 @@ impl ksni::Tray for Tray {
             .into(),
         );
 
+        if adapter.hard_blocked {
+            menu.push(
+                StandardItem {
+                    label: "Turned off by a hardware switch".to_string(),
+                    icon_name: "dialog-information".to_string(),
+                    enabled: false,
+                    ..Default::default()
+                }
+                .into(),
+            );
+        } else if adapter.soft_blocked && self.state.airplane_mode {
+            menu.push(
+                StandardItem {
+                    label: "Turned off by airplane mode".to_string(),
+                    icon_name: "dialog-information".to_string(),
+                    enabled: false,
+                    ..Default::default()
+                }
+                .into(),
+            );
+        }
+
+        menu.push(mode_item(
+            "Discoverable",
+            adapter.discoverable,
+            adapter.discoverable_until,
+            adapter.on,
+            || Action::ToggleDiscoverable,
+        ));
+        menu.push(mode_item(
+            "Pairable",
+            adapter.pairable,
+            adapter.pairable_until,
+            adapter.on,
+            || Action::TogglePairable,
+        ));
+
+        if config.tray.show_airplane_mode {
+            menu.push(
+                CheckmarkItem {
+                    label: "Airplane mode".to_string(),
+                    checked: self.state.airplane_mode,
+                    icon_name: "airplane-mode".to_string(),
+                    activate: Box::new(|this: &mut Self| {
+                        this.send_action(Action::ToggleAirplaneMode).unwrap();
+                    }),
+                    ..Default::default()
+                }
+                .into(),
+            );
+        }
+
+        // Only worth showing when there's more than one adapter to pick from.
+        if self.state.adapters.len() > 1 {
+            let names = self
+                .state
+                .adapters
+                .iter()
+                .map(|adapter| adapter.name.clone())
+                .collect::<Vec<_>>();
+
+            menu.push(
+                SubMenu {
+                    label: format!("Adapter: {}", adapter.alias),
+                    submenu: vec![
+                        RadioGroup {
+                            selected: names
+                                .iter()
+                                .position(|name| *name == adapter.name)
+                                .unwrap_or_default(),
+                            select: Box::new(move |this: &mut Self, index| {
+                                this.send_action(Action::SelectAdapter(names[index].clone()))
+                                    .unwrap();
+                            }),
+                            options: self
+                                .state
+                                .adapters
+                                .iter()
+                                .map(|adapter| RadioItem {
+                                    label: format!("{} ({})", adapter.alias, adapter.name),
+                                    ..Default::default()
+                                })
+                                .collect(),
+                        }
+                        .into(),
+                    ],
+                    ..Default::default()
+                }
+                .into(),
+            );
+        }
+
         menu.push(MenuItem::Separator);
 
         let mut device_list = Vec::<MenuItem<Tray>>::with_capacity(
-            self.state.paired_devices.len() + self.state.available_devices.len(),
+            adapter.paired_devices.len() + adapter.available_devices.len(),
         );
 
         device_list.push(
@@ -129,71 +361,270 @@ impl ksni::Tray for Tray {
 
         device_list.push(MenuItem::Separator);
 
-        for device in &self.state.paired_devices {
-            let local_device = device.clone();
-            let mut name = device.name.clone();
+        for device in &adapter.paired_devices {
+            let mut name = config.device_name(device);
 
-            if let Some(percentage) = device.battery_percentage {
+            if let Some(percentage) = device.battery_percentage
+                && config.tray.show_battery
+            {
                 name = format!("{} ({}%)", name, percentage);
             }
 
-            device_list.push(
+            if let Some(bars) = device.signal_bars(stale_after) {
+                name = format!("{} {}", name, bars);
+            }
+
+            let operation = self.state.operations.get(&device.address);
+
+            if let Some(operation) = operation {
+                name = format!("{} - {}", name, operation.label());
+            } else if device.is_on() {
+                name = format!("{} - Connected", name);
+            } else if device.is_blocked {
+                name = format!("{} - Blocked", name);
+            } else {
+                match device.reconnect {
+                    Some(Reconnect::Trying { .. }) => name = format!("{} - Reconnecting…", name),
+                    Some(Reconnect::Failed) => name = format!("{} - Reconnect failed", name),
+                    _ => (),
+                }
+            }
+
+            let toggle_device = device.clone();
+            let trust_device = device.clone();
+            let favourite_device = device.clone();
+            let address = device.address;
+            let current_name = device.name.clone();
+            let is_blocked = device.is_blocked;
+            // The config's name is shown over the alias, so renaming would change nothing here.
+            let named_in_config = config
+                .device(&address)
+                .is_some_and(|device| device.name.is_some());
+
+            let mut submenu: Vec<MenuItem<Self>> = vec![
+                StandardItem {
+                    label: match operation {
+                        Some(operation) => operation.label().to_string(),
+                        None if device.is_on() => "Disconnect".to_string(),
+                        None => "Connect".to_string(),
+                    },
+                    enabled: operation.is_none(),
+                    activate: Box::new(move |this: &mut Self| {
+                        this.send_action(Action::ToggleDevice(toggle_device.clone()))
+                            .unwrap();
+                    }),
+                    ..Default::default()
+                }
+                .into(),
+            ];
+
+            // Only connected audio devices have a sound card to switch profiles on.
+            if let Some(card) = device
+                .audio
+                .as_ref()
+                .filter(|card| !card.profiles.is_empty())
+            {
+                let profiles = card
+                    .profiles
+                    .iter()
+                    .map(|profile| profile.name.clone())
+                    .collect::<Vec<_>>();
+
+                submenu.push(MenuItem::Separator);
+                submenu.push(
+                    StandardItem {
+                        label: "Profile".to_string(),
+                        enabled: false,
+                        ..Default::default()
+                    }
+                    .into(),
+                );
+                submenu.push(
+                    RadioGroup {
+                        // Without an active profile nothing is ticked, which an index past the
+                        // last option gives.
+                        selected: profiles
+                            .iter()
+                            .position(|name| Some(name) == card.active_profile.as_ref())
+                            .unwrap_or(profiles.len()),
+                        select: Box::new(move |this: &mut Self, index| {
+                            this.send_action(Action::SetAudioProfile(
+                                address,
+                                profiles[index].clone(),
+                            ))
+                            .unwrap();
+                        }),
+                        options: card
+                            .profiles
+                            .iter()
+                            .map(|profile| RadioItem {
+                                label: profile.description.clone(),
+                                ..Default::default()
+                            })
+                            .collect(),
+                    }
+                    .into(),
+                );
+                submenu.push(MenuItem::Separator);
+            }
+
+            submenu.extend([
                 CheckmarkItem {
-                    label: name,
-                    checked: device.is_on(),
+                    label: "Trusted".to_string(),
+                    checked: device.is_trusted,
                     activate: Box::new(move |this: &mut Self| {
-                        this.send_action(Action::ToggleDevice(local_device.clone()))
+                        this.send_action(Action::ToggleTrust(trust_device.clone()))
                             .unwrap();
                     }),
                     ..Default::default()
                 }
                 .into(),
-            );
-        }
+                CheckmarkItem {
+                    label: "Auto-connect".to_string(),
+                    checked: device.is_favourite,
+                    activate: Box::new(move |this: &mut Self| {
+                        this.send_action(Action::ToggleFavourite(favourite_device.clone()))
+                            .unwrap();
+                    }),
+                    ..Default::default()
+                }
+                .into(),
+                StandardItem {
+                    label: if named_in_config {
+                        "Rename… (named in the config file)".to_string()
+                    } else {
+                        "Rename…".to_string()
+                    },
+                    enabled: !named_in_config,
+                    activate: Box::new(move |this: &mut Self| {
+                        this.send_event(AppEvent::RenamePrompt(address, current_name.clone()))
+                            .unwrap();
+                    }),
+                    ..Default::default()
+                }
+                .into(),
+                StandardItem {
+                    label: if is_blocked { "Unblock" } else { "Block" }.to_string(),
+                    activate: Box::new(move |this: &mut Self| {
+                        this.send_action(if is_blocked {
+                            Action::UnblockDevice(address)
+                        } else {
+                            Action::BlockDevice(address)
+                        })
+                        .unwrap();
+                    }),
+                    ..Default::default()
+                }
+                .into(),
+                MenuItem::Separator,
+                StandardItem {
+                    label: "Forget".to_string(),
+                    activate: Box::new(move |this: &mut Self| {
+                        this.send_action(Action::ForgetDevice(address)).unwrap();
+                    }),
+                    ..Default::default()
+                }
+                .into(),
+            ]);
 
-        if self.state.paired_devices.is_empty() {
             device_list.push(
-                StandardItem {
-                    label: "No devices found".to_string(),
-                    enabled: false,
+                SubMenu {
+                    label: name,
+                    icon_name: device.kind.icon_name().to_string(),
+                    submenu,
                     ..Default::default()
                 }
                 .into(),
             );
         }
 
-        device_list.push(MenuItem::Separator);
-
-        device_list.push(
-            StandardItem {
-                label: "Available Devices".to_string(),
-                enabled: false,
-                ..Default::default()
-            }
-            .into(),
-        );
-
-        device_list.push(MenuItem::Separator);
-
-        for device in &self.state.available_devices {
+        if adapter.paired_devices.is_empty() {
             device_list.push(
                 StandardItem {
-                    label: device.name.clone(),
+                    label: "No devices found".to_string(),
+                    enabled: false,
                     ..Default::default()
                 }
                 .into(),
             );
         }
 
-        if self.state.available_devices.is_empty() {
+        if config.tray.show_available_devices {
+            device_list.push(MenuItem::Separator);
+
             device_list.push(
                 StandardItem {
-                    label: "No devices found".to_string(),
+                    label: "Available Devices".to_string(),
                     enabled: false,
                     ..Default::default()
                 }
                 .into(),
             );
+
+            device_list.push(MenuItem::Separator);
+
+            // Grouped by kind so that e.g. the headset being paired is easy to spot in a crowded
+            // room. The sort is stable, so each group stays sorted by signal strength.
+            let mut available_devices = adapter.available_devices.clone();
+            available_devices.sort_by_key(|device| device.kind);
+
+            let grouped = available_devices
+                .first()
+                .is_some_and(|first| available_devices.iter().any(|d| d.kind != first.kind));
+            let mut kind = None;
+
+            for device in &available_devices {
+                let local_device = device.clone();
+
+                if grouped && kind != Some(device.kind) {
+                    kind = Some(device.kind);
+
+                    device_list.push(
+                        StandardItem {
+                            label: device.kind.label().to_string(),
+                            enabled: false,
+                            ..Default::default()
+                        }
+                        .into(),
+                    );
+                }
+
+                let mut label = config.device_name(device);
+                let operation = self.state.operations.get(&device.address);
+
+                if let Some(bars) = device.signal_bars(stale_after) {
+                    label = format!("{} {}", label, bars);
+                }
+
+                if let Some(operation) = operation {
+                    label = format!("{} - {}", label, operation.label());
+                }
+
+                device_list.push(
+                    StandardItem {
+                        label,
+                        enabled: operation.is_none(),
+                        icon_name: device.kind.icon_name().to_string(),
+                        activate: Box::new(move |this: &mut Self| {
+                            this.send_action(Action::PairDevice(local_device.clone()))
+                                .unwrap();
+                        }),
+                        ..Default::default()
+                    }
+                    .into(),
+                );
+            }
+
+            if available_devices.is_empty() {
+                device_list.push(
+                    StandardItem {
+                        label: "No devices found".to_string(),
+                        enabled: false,
+                        ..Default::default()
+                    }
+                    .into(),
+                );
+            }
         }
 
         menu.push(
@@ -205,13 +636,151 @@ impl ksni::Tray for Tray {
             .into(),
         );
 
+        // Blocked paired devices stay in "Devices", where they're blocked and unblocked.
+        if !adapter.blocked_devices.is_empty() {
+            let blocked_list = adapter
+                .blocked_devices
+                .iter()
+                .map(|device| {
+                    let address = device.address;
+
+                    SubMenu {
+                        label: config.device_name(device),
+                        icon_name: device.kind.icon_name().to_string(),
+                        submenu: vec![
+                            StandardItem {
+                                label: "Unblock".to_string(),
+                                activate: Box::new(move |this: &mut Self| {
+                                    this.send_action(Action::UnblockDevice(address)).unwrap();
+                                }),
+                                ..Default::default()
+                            }
+                            .into(),
+                        ],
+                        ..Default::default()
+                    }
+                    .into()
+                })
+                .collect();
+
+            menu.push(
+                SubMenu {
+                    label: "Blocked devices".to_string(),
+                    submenu: blocked_list,
+                    ..Default::default()
+                }
+                .into(),
+            );
+        }
+
+        // Another program scanning doesn't stop us from scanning, but it's theirs to stop.
+        let scanning = adapter.scan_started_here;
+
+        if config.tray.show_scan_item {
+            menu.push(
+                StandardItem {
+                    label: if scanning {
+                        "Stop scanning".to_string()
+                    } else {
+                        "Scan for devices".to_string()
+                    },
+                    enabled: adapter.on,
+                    activate: Box::new(move |this: &mut Self| {
+                        this.send_action(if scanning {
+                            Action::StopScan
+                        } else {
+                            Action::StartScan
+                        })
+                        .unwrap();
+                    }),
+                    ..Default::default()
+                }
+                .into(),
+            );
+        }
+
         menu
     }
 }
 
+/// A checkmark for discoverable or pairable mode, with the time left while it counts down.
+fn mode_item(
+    label: &str,
+    on: bool,
+    until: Option<Instant>,
+    enabled: bool,
+    action: fn() -> Action,
+) -> MenuItem<Tray> {
+    let left = until
+        .filter(|_| on)
+        .map(|until| until.saturating_duration_since(Instant::now()))
+        .filter(|left| !left.is_zero());
+
+    CheckmarkItem {
+        label: match left {
+            Some(left) => countdown_label(label, left),
+            None => label.to_string(),
+        },
+        checked: on,
+        enabled,
+        activate: Box::new(move |this: &mut Tray| {
+            this.send_action(action()).unwrap();
+        }),
+        ..Default::default()
+    }
+    .into()
+}
+
+/// Rounds up, so that the countdown ends on "0:01" rather than "0:00".
+fn countdown_label(label: &str, left: Duration) -> String {
+    let secs = left.as_secs() + u64::from(left.subsec_nanos() > 0);
+    format!("{} ({}:{:02})", label, secs / 60, secs % 60)
+}
+
+/// When the shown countdowns need redrawing, i.e. when the number of whole seconds left in one
+/// of them changes.
+fn next_countdown_tick(state: &BTState, now: Instant) -> Option<Duration> {
+    let adapter = state.adapter()?;
+
+    [
+        (adapter.discoverable, adapter.discoverable_until),
+        (adapter.pairable, adapter.pairable_until),
+    ]
+    .into_iter()
+    .filter_map(|(on, until)| until.filter(|_| on))
+    .filter(|until| *until > now)
+    .map(|until| match (until - now).subsec_nanos() {
+        0 => Duration::from_secs(1),
+        nanos => Duration::from_nanos(nanos.into()),
+    })
+    .min()
+}
+
+/// Loads the configured images. Ones that can't be read are logged and left to the theme.
+fn load_custom_icons(icons: &TrayIconsConfig) -> HashMap<IconState, ksni::Icon> {
+    IconState::ALL
+        .into_iter()
+        .filter_map(|state| {
+            let path = state.custom_path(icons)?;
+
+            match image::open(path) {
+                Ok(image) => Some((state, icon_from_image(image))),
+                Err(e) => {
+                    error!("Failed to load tray icon {}. {e:?}", path.display());
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
 fn get_icon_from_image_bytes(image_bytes: &[u8]) -> ksni::Icon {
     let img = image::load_from_memory_with_format(image_bytes, image::ImageFormat::Png)
         .expect("valid image");
+    icon_from_image(img)
+}
+
+fn icon_from_image(img: image::DynamicImage) -> ksni::Icon {
     let (width, height) = img.dimensions();
     let mut data = img.into_rgba8().into_vec();
     assert_eq!(data.len() % 4, 0);
@@ -237,19 +806,181 @@ pub async fn init_tray(app_tx: Sender<AppEvent>) -> Result<Sender<TrayEvent>> {
     let (tx, mut rx) = channel::<TrayEvent>(32);
 
     let tokio_handle = tokio::runtime::Handle::current();
+    // Weak, so that the task still ends once everyone else has dropped their sender.
+    let clear_tx = tx.downgrade();
     tokio_handle.spawn(async move {
-        while let Some(event) = rx.recv().await {
+        let mut failure_id = 0;
+        let mut shown = BTState::default();
+
+        loop {
+            let tick = next_countdown_tick(&shown, Instant::now());
+
+            // BlueZ says nothing while a mode counts down, so the time left is redrawn from here.
+            let event = tokio::select! {
+                event = rx.recv() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+                _ = async {
+                    match tick {
+                        Some(tick) => tokio::time::sleep(tick).await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    handle.update(|_| ()).await;
+                    continue;
+                }
+            };
+
             match event {
                 TrayEvent::Update(state) => {
+                    shown = state.clone();
                     handle
                         .update(|tray| {
                             tray.update(state);
                         })
                         .await;
                 }
+                TrayEvent::Failure(failure) => {
+                    failure_id += 1;
+                    let id = failure_id;
+
+                    handle
+                        .update(|tray| {
+                            tray.failure = Some((id, failure));
+                        })
+                        .await;
+
+                    let clear_tx = clear_tx.clone();
+                    tokio::spawn(async move {
+                        tokio::time::sleep(Duration::from_secs(FAILURE_DISPLAY_SECS)).await;
+
+                        if let Some(clear_tx) = clear_tx.upgrade() {
+                            let _ = clear_tx.send(TrayEvent::ClearFailure(id)).await;
+                        }
+                    });
+                }
+                TrayEvent::ClearFailure(id) => {
+                    handle
+                        .update(|tray| {
+                            if tray.failure.as_ref().is_some_and(|(shown, _)| *shown == id) {
+                                tray.failure = None;
+                            }
+                        })
+                        .await;
+                }
             };
         }
     });
 
     Ok(tx)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bluetooth::BTAdapter;
+
+    fn state(adapter: BTAdapter) -> BTState {
+        BTState {
+            adapters: vec![BTAdapter {
+                name: "hci0".to_string(),
+                ..adapter
+            }],
+            selected_adapter: Some("hci0".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn icon_follows_the_adapter() {
+        let on = BTAdapter {
+            on: true,
+            ..Default::default()
+        };
+
+        assert_eq!(IconState::from_state(&BTState::default()), IconState::Off);
+        assert_eq!(
+            IconState::from_state(&state(BTAdapter::default())),
+            IconState::Off
+        );
+        assert_eq!(IconState::from_state(&state(on.clone())), IconState::On);
+        assert_eq!(
+            IconState::from_state(&state(BTAdapter {
+                scanning: true,
+                ..on.clone()
+            })),
+            IconState::Scanning
+        );
+        assert_eq!(
+            IconState::from_state(&state(BTAdapter {
+                hard_blocked: true,
+                ..Default::default()
+            })),
+            IconState::Blocked
+        );
+
+        // Turned off from the menu, which soft blocks the adapter but isn't airplane mode.
+        let soft_blocked = state(BTAdapter {
+            soft_blocked: true,
+            ..Default::default()
+        });
+        assert_eq!(IconState::from_state(&soft_blocked), IconState::Off);
+        assert_eq!(
+            IconState::from_state(&BTState {
+                airplane_mode: true,
+                ..soft_blocked
+            }),
+            IconState::Blocked
+        );
+    }
+
+    #[test]
+    fn countdowns_round_up_to_the_second() {
+        assert_eq!(
+            countdown_label("Discoverable", Duration::from_secs(180)),
+            "Discoverable (3:00)"
+        );
+        assert_eq!(
+            countdown_label("Discoverable", Duration::from_millis(59_001)),
+            "Discoverable (1:00)"
+        );
+        assert_eq!(
+            countdown_label("Pairable", Duration::from_millis(1)),
+            "Pairable (0:01)"
+        );
+    }
+
+    #[test]
+    fn countdowns_are_redrawn_every_second() {
+        let now = Instant::now();
+        let mut adapter = BTAdapter {
+            discoverable: true,
+            discoverable_until: Some(now + Duration::from_millis(179_250)),
+            pairable_until: Some(now + Duration::from_millis(500)),
+            ..Default::default()
+        };
+
+        // Pairable mode is off, so its countdown isn't shown.
+        assert_eq!(
+            next_countdown_tick(&state(adapter.clone()), now),
+            Some(Duration::from_millis(250))
+        );
+
+        adapter.pairable = true;
+        assert_eq!(
+            next_countdown_tick(&state(adapter.clone()), now),
+            Some(Duration::from_millis(250))
+        );
+
+        adapter.discoverable_until = Some(now + Duration::from_secs(60));
+        assert_eq!(
+            next_countdown_tick(&state(adapter.clone()), now),
+            Some(Duration::from_millis(500))
+        );
+
+        adapter.discoverable = false;
+        adapter.pairable_until = Some(now);
+        assert_eq!(next_countdown_tick(&state(adapter), now), None);
+    }
+}